@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+
+use crate::expand::valid_name;
+use crate::Verbosity;
+
+pub const USAGE: &str = "usage: pma [options] [name=value ...] [--] [target ...]
+
+options:
+  -f, --file <file>       read rules from <file>, \"-\" for stdin
+  -C, --directory <dir>   change to <dir> before doing anything
+  -j, --jobs <n>          build at most <n> targets at the same time
+  -k, --keep-going        build what does not depend on failed targets
+      --keep-failed       move failed targets to <target>.failed, not delete
+  -n, --dry-run           print commands instead of running them
+  -q, --question          run nothing, exit with 1 if anything is out of date
+      --hash              compare file contents instead of modification times
+      --graph <format>    print the dependency graph as \"dot\" or \"json\"
+      --log-dir <dir>     capture the output of each target in a log under <dir>
+      --set <name=value>  override parameter <name>, same as name=value
+  -v, --verbose           print more, give twice for debugging output
+  -h, --help              print this help and exit
+      --version           print version and exit";
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Build,
+    Help,
+    Version,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub action: Action,
+    pub jobs: usize,
+    pub keep_going: bool,
+    pub keep_failed: bool,
+    pub dry_run: bool,
+    pub question: bool,
+    pub hash: bool,
+    pub graph: Option<GraphFormat>,
+    pub log_dir: Option<String>,
+    pub file: Option<String>,
+    pub directory: Option<String>,
+    pub verbosity: Option<Verbosity>,
+    pub overrides: Vec<(String, String)>,
+    pub targets: VecDeque<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            action: Action::Build,
+            jobs: 1,
+            keep_going: false,
+            keep_failed: false,
+            dry_run: false,
+            question: false,
+            hash: false,
+            graph: None,
+            log_dir: None,
+            file: None,
+            directory: None,
+            verbosity: None,
+            overrides: Vec::new(),
+            targets: VecDeque::new(),
+        }
+    }
+
+    fn verbose(&mut self) {
+        self.verbosity = match self.verbosity {
+            None => Some(Verbosity::Verbose),
+            Some(_) => Some(Verbosity::Debug),
+        };
+    }
+}
+
+// Fetches the value of option `name`, either attached to it or as the next
+// argument.
+fn value<T>(name: &str, attached: Option<String>, args: &mut T) -> Result<String, String>
+where
+    T: Iterator<Item = String>,
+{
+    match attached.or_else(|| args.next()) {
+        Some(v) => Ok(v),
+        None => Err(format!("{}: expecting a value", name)),
+    }
+}
+
+fn jobs(name: &str, n: &str) -> Result<usize, String> {
+    match n.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{}: invalid number of jobs: {:?}", name, n)),
+    }
+}
+
+fn graph(name: &str, format: &str) -> Result<GraphFormat, String> {
+    match format {
+        "dot" => Ok(GraphFormat::Dot),
+        "json" => Ok(GraphFormat::Json),
+        _ => Err(format!("{}: unknown graph format: {:?}", name, format)),
+    }
+}
+
+// Splits "name=value" if the name is a valid parameter name.
+fn assignment(arg: &str) -> Option<(String, String)> {
+    let (name, value) = arg.split_once('=')?;
+    if !valid_name(name) {
+        return None;
+    }
+    Some((name.to_string(), value.to_string()))
+}
+
+fn long<T>(opts: &mut Options, arg: &str, args: &mut T) -> Result<(), String>
+where
+    T: Iterator<Item = String>,
+{
+    let (name, attached) = match arg.find('=') {
+        Some(i) => (&arg[..i], Some(arg[i + 1..].to_string())),
+        None => (arg, None),
+    };
+    let flag = |attached: Option<String>| match attached {
+        Some(_) => Err(format!("{}: not expecting a value", name)),
+        None => Ok(()),
+    };
+    match name {
+        "--file" => opts.file = Some(value(name, attached, args)?),
+        "--directory" => opts.directory = Some(value(name, attached, args)?),
+        "--jobs" => opts.jobs = jobs(name, &value(name, attached, args)?)?,
+        "--graph" => opts.graph = Some(graph(name, &value(name, attached, args)?)?),
+        "--log-dir" => opts.log_dir = Some(value(name, attached, args)?),
+        "--set" => {
+            let v = value(name, attached, args)?;
+            match assignment(&v) {
+                Some(a) => opts.overrides.push(a),
+                None => return Err(format!("{}: expecting name=value: {:?}", name, v)),
+            }
+        }
+        "--keep-going" => {
+            flag(attached)?;
+            opts.keep_going = true;
+        }
+        "--keep-failed" => {
+            flag(attached)?;
+            opts.keep_failed = true;
+        }
+        "--dry-run" => {
+            flag(attached)?;
+            opts.dry_run = true;
+        }
+        "--question" => {
+            flag(attached)?;
+            opts.question = true;
+        }
+        "--hash" => {
+            flag(attached)?;
+            opts.hash = true;
+        }
+        "--verbose" => {
+            flag(attached)?;
+            opts.verbose();
+        }
+        "--help" => {
+            flag(attached)?;
+            opts.action = Action::Help;
+        }
+        "--version" => {
+            flag(attached)?;
+            opts.action = Action::Version;
+        }
+        _ => return Err(format!("unknown option: {}", name)),
+    }
+    Ok(())
+}
+
+// Handles a group of short options such as "-vv" or "-j4".
+fn short<T>(opts: &mut Options, arg: &str, args: &mut T) -> Result<(), String>
+where
+    T: Iterator<Item = String>,
+{
+    for (i, c) in arg.char_indices().skip(1) {
+        let name = format!("-{}", c);
+        let rest = &arg[i + c.len_utf8()..];
+        let attached = if rest.is_empty() {
+            None
+        } else {
+            Some(rest.to_string())
+        };
+        match c {
+            'f' => opts.file = Some(value(&name, attached, args)?),
+            'C' => opts.directory = Some(value(&name, attached, args)?),
+            'j' => opts.jobs = jobs(&name, &value(&name, attached, args)?)?,
+            'k' => {
+                opts.keep_going = true;
+                continue;
+            }
+            'n' => {
+                opts.dry_run = true;
+                continue;
+            }
+            'q' => {
+                opts.question = true;
+                continue;
+            }
+            'v' => {
+                opts.verbose();
+                continue;
+            }
+            'h' => {
+                opts.action = Action::Help;
+                continue;
+            }
+            _ => return Err(format!("unknown option: {}", name)),
+        }
+        // The option consumed the rest of the group as its value.
+        break;
+    }
+    Ok(())
+}
+
+// Parses the command line without the program name.
+pub fn parse_args<T>(mut args: T) -> Result<Options, String>
+where
+    T: Iterator<Item = String>,
+{
+    let mut opts = Options::new();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            opts.targets.extend(args);
+            break;
+        } else if arg.starts_with("--") {
+            long(&mut opts, &arg, &mut args)?;
+        } else if arg.starts_with('-') && arg != "-" {
+            short(&mut opts, &arg, &mut args)?;
+        } else if let Some(a) = assignment(&arg) {
+            opts.overrides.push(a);
+        } else {
+            opts.targets.push_back(arg);
+        }
+    }
+    Ok(opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_cli_options() {
+        let opts = parse(&["-vvnk", "-j4", "-f", "x.pma", "--directory=src", "all"]).unwrap();
+        assert_eq!(Action::Build, opts.action);
+        assert!(opts.dry_run);
+        assert!(opts.keep_going);
+        assert_eq!(Some(Verbosity::Debug), opts.verbosity);
+        assert_eq!(4, opts.jobs);
+        assert_eq!(Some("x.pma".to_string()), opts.file);
+        assert_eq!(Some("src".to_string()), opts.directory);
+        assert_eq!(None, opts.log_dir);
+        assert_eq!(vec!["all".to_string()], Vec::from(opts.targets));
+    }
+
+    #[test]
+    fn test_cli_separator() {
+        let opts = parse(&[
+            "-v",
+            "--question",
+            "--hash",
+            "--graph",
+            "dot",
+            "--log-dir=logs",
+            "--",
+            "-v",
+            "clean",
+        ])
+        .unwrap();
+        assert_eq!(Some(Verbosity::Verbose), opts.verbosity);
+        assert_eq!(Some(GraphFormat::Dot), opts.graph);
+        assert_eq!(Some("logs".to_string()), opts.log_dir);
+        assert!(opts.question);
+        assert!(opts.hash);
+        assert!(!opts.keep_failed);
+        assert_eq!(
+            vec!["-v".to_string(), "clean".to_string()],
+            Vec::from(opts.targets)
+        );
+    }
+
+    #[test]
+    fn test_cli_errors() {
+        assert_eq!(Err("unknown option: -x".to_string()), parse(&["-vx"]));
+        assert_eq!(
+            Err("unknown option: --frobnicate".to_string()),
+            parse(&["--frobnicate"])
+        );
+        assert_eq!(Err("-f: expecting a value".to_string()), parse(&["-f"]));
+        assert_eq!(
+            Err("--help: not expecting a value".to_string()),
+            parse(&["--help=yes"])
+        );
+        assert!(parse(&["-j", "0"]).is_err());
+        assert!(parse(&["--graph=svg"]).is_err());
+        assert!(parse(&["--set", "1X=y"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_overrides() {
+        let opts = parse(&[
+            "CC=clang",
+            "all",
+            "--set",
+            "CFLAGS=-O2 -g",
+            "x=y=z",
+            "--",
+            "A=b",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("CC".to_string(), "clang".to_string()),
+                ("CFLAGS".to_string(), "-O2 -g".to_string()),
+                ("x".to_string(), "y=z".to_string()),
+            ],
+            opts.overrides
+        );
+        assert_eq!(
+            vec!["all".to_string(), "A=b".to_string()],
+            Vec::from(opts.targets)
+        );
+        let opts = parse(&["a/b=c"]).unwrap();
+        assert_eq!(vec!["a/b=c".to_string()], Vec::from(opts.targets));
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs;
+use std::io;
+
+// Returns the prerequisites listed in a Makefile-style dependency file, as
+// written by `cc -MD`, in order and without duplicates. Targets of all rules
+// are skipped, which also covers the empty rules added by `cc -MP`.
+pub fn parse(s: &str) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    let joined = s.replace("\\\r\n", " ").replace("\\\n", " ");
+    for line in joined.lines() {
+        let words = split(line);
+        let colon = match words.iter().position(|w| w.ends_with(':')) {
+            Some(i) => i,
+            None => continue,
+        };
+        for word in words.into_iter().skip(colon + 1) {
+            if !ret.contains(&word) {
+                ret.push(word);
+            }
+        }
+    }
+    ret
+}
+
+pub fn read(path: &str) -> io::Result<Vec<String>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+// Splits a line into words at unescaped whitespace. "\ " is a space within a
+// name and "$$" a dollar sign. A name is cut short by a comment.
+fn split(line: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut cur = String::new();
+    let mut it = line.chars().peekable();
+    while let Some(c) = it.next() {
+        match c {
+            '\\' if it.peek() == Some(&' ') => {
+                cur.push(' ');
+                it.next();
+            }
+            '$' if it.peek() == Some(&'$') => {
+                cur.push('$');
+                it.next();
+            }
+            '#' => break,
+            c if c.is_whitespace() => {
+                if !cur.is_empty() {
+                    ret.push(cur);
+                    cur = String::new();
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        ret.push(cur);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depfile_parse() {
+        let s = "foo.o: foo.c foo.h \\\n  sub/my\\ file.h co$$t.h\n\nfoo.h:\nsub/my\\ file.h:\n";
+        assert_eq!(vec!["foo.c", "foo.h", "sub/my file.h", "co$t.h"], parse(s));
+        assert_eq!(vec!["b", "c"], parse("a: b c\nd: c"));
+        assert!(parse("# nothing here\n").is_empty());
+    }
+}
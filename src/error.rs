@@ -0,0 +1,245 @@
+use std::error;
+use std::fmt;
+
+use crate::sexpr::Pos;
+
+// The stage an error comes from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+    Lex,
+    Parse,
+    Syntax,
+    Expansion,
+    Build,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Lex => write!(f, "lex error"),
+            Kind::Parse => write!(f, "sexpr error"),
+            Kind::Syntax => write!(f, "syntax error"),
+            Kind::Expansion => write!(f, "expansion error"),
+            Kind::Build => write!(f, "build error"),
+        }
+    }
+}
+
+// An error with as much of its location as is known. A column of zero means
+// that only the line is known.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Error {
+    kind: Kind,
+    msg: String,
+    file: Option<String>,
+    pos: Option<Pos>,
+    line: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: Kind, msg: &str) -> Error {
+        Error {
+            kind,
+            msg: msg.to_string(),
+            file: None,
+            pos: None,
+            line: None,
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn pos(&self) -> Option<Pos> {
+        self.pos
+    }
+
+    // Places the error at `pos`, unless it already has a location. Errors
+    // from included files keep theirs.
+    pub fn at(mut self, pos: Pos) -> Error {
+        if self.pos.is_none() && self.file.is_none() {
+            self.pos = Some(pos);
+        }
+        self
+    }
+
+    // Names the file the error is in, unless an included file already did.
+    pub fn in_file(mut self, file: Option<&str>) -> Error {
+        if self.file.is_none() {
+            self.file = file.map(|f| f.to_string());
+        }
+        self
+    }
+
+    // Picks the line the error is on from `src`, the contents of its file.
+    pub fn quoting(mut self, src: &str) -> Error {
+        if let (None, Some(pos)) = (&self.line, self.pos) {
+            if pos.lineno > 0 {
+                self.line = src
+                    .lines()
+                    .nth(pos.lineno as usize - 1)
+                    .map(|l| l.to_string());
+            }
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        match self.pos {
+            Some(pos) if pos.col > 0 => write!(f, "{}:{}:", pos.lineno, pos.col)?,
+            Some(pos) => write!(f, "{}:", pos.lineno)?,
+            None => (),
+        }
+        if self.file.is_some() || self.pos.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "{}: {}", self.kind, self.msg)?;
+        if let (Some(line), Some(pos)) = (&self.line, self.pos) {
+            // Tabs are kept so that the caret lines up with the quoted line.
+            let indent: String = line
+                .chars()
+                .take(pos.col.saturating_sub(1) as usize)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(f, "\n    {}", line)?;
+            if pos.col > 0 {
+                write!(f, "\n    {}^", indent)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
+// All the errors found in one pass, in the order they were found.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Errors(Vec<Error>);
+
+impl Errors {
+    pub fn new() -> Errors {
+        Errors(Vec::new())
+    }
+
+    pub fn push(&mut self, e: Error) {
+        self.0.push(e);
+    }
+
+    pub fn append(&mut self, mut other: Errors) {
+        self.0.append(&mut other.0);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Error> {
+        self.0.iter()
+    }
+
+    pub fn map<F>(self, f: F) -> Errors
+    where
+        F: Fn(Error) -> Error,
+    {
+        Errors(self.0.into_iter().map(f).collect())
+    }
+
+    // Gives `value` if nothing went wrong.
+    pub fn or_ok<T>(self, value: T) -> Result<T, Errors> {
+        if self.is_empty() {
+            Ok(value)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl From<Error> for Errors {
+    fn from(e: Error) -> Errors {
+        Errors(vec![e])
+    }
+}
+
+impl IntoIterator for Errors {
+    type Item = Error;
+    type IntoIter = std::vec::IntoIter<Error>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Errors {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let e = Error::new(Kind::Syntax, "target: expecting target name");
+        assert_eq!("syntax error: target: expecting target name", e.to_string());
+
+        let src = "(set \"X\" \"x\")\n\t(target 123)\n";
+        let e = e.at(Pos::new(2, 10)).in_file(Some("a.pma")).quoting(src);
+        assert_eq!(
+            "a.pma:2:10: syntax error: target: expecting target name\n    \t(target 123)\n    \t        ^",
+            e.to_string()
+        );
+
+        // The first location given sticks.
+        let e = e.at(Pos::new(1, 1)).in_file(Some("b.pma")).quoting("other");
+        assert_eq!(Some("a.pma"), e.file());
+        assert_eq!(Some(Pos::new(2, 10)), e.pos());
+
+        let e = Error::new(Kind::Expansion, "unknown").at(Pos::new(3, 0));
+        assert_eq!("3: expansion error: unknown", e.to_string());
+    }
+
+    #[test]
+    fn test_errors_display() {
+        let mut errs = Errors::new();
+        assert_eq!(Ok(1), errs.clone().or_ok(1));
+        errs.push(Error::new(Kind::Lex, "unexpected character").at(Pos::new(1, 2)));
+        errs.append(
+            Error::new(Kind::Lex, "unterminated string")
+                .at(Pos::new(4, 1))
+                .into(),
+        );
+        let errs = errs.map(|e| e.in_file(Some("a.pma")));
+        assert_eq!(2, errs.len());
+        assert_eq!(
+            "a.pma:1:2: lex error: unexpected character\na.pma:4:1: lex error: unterminated string",
+            errs.to_string()
+        );
+    }
+}
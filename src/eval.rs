@@ -1,147 +1,680 @@
-use crate::recipe::Recipe;
-use crate::recipe::Thing;
-use crate::sexpr::SExpr;
+use crate::error::{Error, Errors, Kind};
+use crate::expand::valid_name;
+use crate::glob;
+use crate::lex::lex;
+use crate::parse::parse;
+use crate::recipe::{match_pattern, Origin, Recipe, TargetOptions, Thing, Value};
+use crate::sexpr::{Pos, SExpr};
 
-use std::error::Error;
-use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
-pub struct SyntaxError {
-    msg: String,
+fn syntax(msg: &str) -> Error {
+    Error::new(Kind::Syntax, msg)
 }
 
-impl fmt::Display for SyntaxError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "syntax error: {}", self.msg)
-    }
+// A file being evaluated. `path` is canonical and missing for stdin.
+struct Source {
+    name: String,
+    path: Option<PathBuf>,
 }
 
-impl Error for SyntaxError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
-    }
+// The files being evaluated, innermost last.
+struct Context {
+    stack: Vec<Source>,
 }
 
-impl SyntaxError {
-    fn new(msg: &str) -> SyntaxError {
-        SyntaxError {
-            msg: msg.to_string(),
+impl Context {
+    fn name(&self) -> Option<&str> {
+        self.stack.last().map(|s| s.name.as_str())
+    }
+
+    // Included files are looked up relative to the including file. Going by
+    // the name keeps the result readable in messages.
+    fn resolve(&self, path: &str) -> PathBuf {
+        let dir = match self.stack.last() {
+            Some(Source {
+                name,
+                path: Some(_),
+            }) => Path::new(name).parent().map(|p| p.to_path_buf()),
+            _ => None,
+        };
+        match (dir, Path::new(path)) {
+            (Some(dir), p) if p.is_relative() => dir.join(p),
+            (_, p) => p.to_path_buf(),
         }
     }
 }
 
-fn eval_stridlist(sexpr: &[SExpr]) -> Result<Vec<Thing>, SyntaxError> {
+// Expressions inside dependency lists are spliced in as actual files.
+fn eval_stridlist(rec: &Recipe, sexpr: &[SExpr]) -> Result<Vec<Thing>, Error> {
     let mut ret = Vec::new();
     for subexpr in sexpr.iter() {
         match subexpr {
-            SExpr::Str(s) => ret.push(Thing::Actual(s.to_string())),
-            SExpr::Id(i) => ret.push(Thing::Pseudo(i.to_string())),
-            _ => return Err(SyntaxError::new("not a string or identifier")),
+            SExpr::Str(s, _) => ret.push(Thing::Actual(s.to_string())),
+            SExpr::Id(i, _) => ret.push(Thing::Pseudo(i.to_string())),
+            SExpr::List(..) => ret.extend(
+                eval_expr(rec, subexpr)?
+                    .items()
+                    .into_iter()
+                    .map(Thing::Actual),
+            ),
+            _ => return Err(syntax("not a string, identifier or expression").at(subexpr.pos())),
         }
     }
     Ok(ret)
 }
 
-fn eval_strlist(sexpr: &[SExpr]) -> Result<Vec<String>, SyntaxError> {
+// Evaluates a value expression. Strings have their parameters expanded, and
+// lists are applications of one of the following:
+//
+//   (list <expr> ...)             the items of all expressions
+//   (var "<name>")                the value of a parameter
+//   (join "<sep>" <expr>)         the items joined with a separator
+//   (map "<from>" "<to>" <expr>)  the items with "%" patterns substituted
+//   (filter "<pattern>" <expr>)   the items matching a "%" pattern
+//   (len <expr>)                  the number of items
+//   (glob "<pattern>" ...)        the existing files matching shell patterns
+//
+fn eval_expr(rec: &Recipe, sexpr: &SExpr) -> Result<Value, Error> {
+    let l = match sexpr {
+        SExpr::Str(s, _) => {
+            return match rec.expand(s) {
+                Ok(s) => Ok(Value::Str(s)),
+                Err(e) => Err(e.at(sexpr.pos())),
+            }
+        }
+        SExpr::Id(i, _) => {
+            return Err(syntax(&format!("unexpected identifier: {}", i)).at(sexpr.pos()))
+        }
+        SExpr::List(l, _) => l,
+        _ => return Err(syntax("expecting an expression").at(sexpr.pos())),
+    };
+    let op = match l.first() {
+        Some(SExpr::Id(i, _)) => i.as_str(),
+        _ => return Err(syntax("expecting an identifier").at(sexpr.pos())),
+    };
+    let args = &l[1..];
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(syntax(&format!("{}: expecting {} list elements", op, n)).at(sexpr.pos()))
+        }
+    };
+    let string = |sexpr: &SExpr| match eval_expr(rec, sexpr)? {
+        Value::Str(s) => Ok(s),
+        Value::List(_) => Err(syntax(&format!("{}: expecting a string", op)).at(sexpr.pos())),
+    };
+    let items = |sexpr: &SExpr| eval_expr(rec, sexpr).map(|v| v.items());
+    match op {
+        "list" => {
+            let mut ret = Vec::new();
+            for arg in args.iter() {
+                ret.extend(items(arg)?);
+            }
+            Ok(Value::List(ret))
+        }
+        "var" => {
+            arity(1)?;
+            let name = string(&args[0])?;
+            match rec.get_var(&name) {
+                Some(v) => Ok(v.clone()),
+                None => Err(syntax(&format!("var: unknown parameter: {}", name)).at(args[0].pos())),
+            }
+        }
+        "join" => {
+            arity(2)?;
+            Ok(Value::Str(items(&args[1])?.join(&string(&args[0])?)))
+        }
+        "map" => {
+            arity(3)?;
+            let (from, to) = (string(&args[0])?, string(&args[1])?);
+            Ok(Value::List(
+                items(&args[2])?
+                    .into_iter()
+                    .map(|item| match match_pattern(&from, &item) {
+                        Some(stem) => to.replacen('%', stem, 1),
+                        None => item,
+                    })
+                    .collect(),
+            ))
+        }
+        "filter" => {
+            arity(2)?;
+            let pattern = string(&args[0])?;
+            Ok(Value::List(
+                items(&args[1])?
+                    .into_iter()
+                    .filter(|item| match_pattern(&pattern, item).is_some())
+                    .collect(),
+            ))
+        }
+        "len" => {
+            arity(1)?;
+            Ok(Value::Str(items(&args[0])?.len().to_string()))
+        }
+        "glob" => {
+            if args.is_empty() {
+                return Err(syntax("glob: expecting at least one pattern").at(sexpr.pos()));
+            }
+            let mut ret = Vec::new();
+            for arg in args.iter() {
+                ret.extend(glob::glob(&string(arg)?));
+            }
+            Ok(Value::List(ret))
+        }
+        "env" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(syntax("env: expecting 1 or 2 list elements").at(sexpr.pos()));
+            }
+            let name = string(&args[0])?;
+            match (std::env::var(&name), args.get(1)) {
+                (Ok(v), _) => Ok(Value::Str(v)),
+                (Err(_), Some(default)) => eval_expr(rec, default),
+                (Err(e), None) => Err(syntax(&format!("env: {}: {}", name, e)).at(args[0].pos())),
+            }
+        }
+        _ => Err(syntax(&format!("unknown expression: {}", op)).at(l[0].pos())),
+    }
+}
+
+fn eval_strlist(sexpr: &[SExpr]) -> Result<Vec<String>, Error> {
     let mut ret = Vec::new();
     for subexpr in sexpr.iter() {
         match subexpr {
-            SExpr::Str(s) => ret.push(s.to_string()),
-            _ => return Err(SyntaxError::new("not a string")),
+            SExpr::Str(s, _) => ret.push(s.to_string()),
+            _ => return Err(syntax("not a string").at(subexpr.pos())),
         }
     }
     Ok(ret)
 }
 
-fn eval_target(rec: &mut Recipe, sexpr: &[SExpr]) -> Result<(), SyntaxError> {
-    if sexpr.len() != 4 {
-        return Err(SyntaxError::new("target: expecting 4 list elements"));
+// Target options follow the commands of a target:
+//
+//   (depfile "<path>")  further dependencies listed by the commands
+//   (precious)          keep the target even if its commands fail
+//
+// Values of target parameters are evaluated where they are set, like global
+// ones.
+fn eval_options(rec: &Recipe, sexpr: &[SExpr]) -> Result<TargetOptions, Error> {
+    let mut options = TargetOptions::default();
+    for option in sexpr.iter() {
+        let l = match option {
+            SExpr::List(l, _) => l,
+            _ => return Err(syntax("expecting a list of target options").at(option.pos())),
+        };
+        let err = |msg: &str| syntax(msg).at(option.pos());
+        match l.first() {
+            Some(SExpr::Id(i, _)) if i == "depfile" => match &l[1..] {
+                [SExpr::Str(path, _)] => options.depfile = Some(path.to_string()),
+                _ => return Err(err("depfile: expecting file name as string")),
+            },
+            Some(SExpr::Id(i, _)) if i == "precious" => match &l[1..] {
+                [] => options.precious = true,
+                _ => return Err(err("precious: expecting no arguments")),
+            },
+            Some(SExpr::Id(i, _)) if i == "set" => match &l[1..] {
+                [name, value] => {
+                    let name = eval_name(name)?;
+                    // Target parameters are always expanded again when
+                    // inserted, so literal values are escaped instead.
+                    let escape = |s: &String| s.replace('$', "$$");
+                    let value = match (literal(rec, value), eval_expr(rec, value)?) {
+                        (true, Value::Str(s)) => Value::Str(escape(&s)),
+                        (true, Value::List(l)) => Value::List(l.iter().map(escape).collect()),
+                        (false, value) => value,
+                    };
+                    options.vars.push((name.to_string(), value));
+                }
+                _ => return Err(err("set: expecting 2 list elements")),
+            },
+            Some(SExpr::Id(i, _)) => return Err(err(&format!("unknown target option: {}", i))),
+            _ => return Err(err("expecting a target option")),
+        }
+    }
+    Ok(options)
+}
+
+fn eval_target(rec: &mut Recipe, ctx: &Context, sexpr: &[SExpr], pos: Pos) -> Result<(), Error> {
+    if sexpr.len() < 4 {
+        return Err(syntax("target: expecting 4 or more list elements"));
     }
     let name = match &sexpr[1] {
-        SExpr::Str(s) => Thing::Actual(s.to_string()),
-        SExpr::Id(i) => Thing::Pseudo(i.to_string()),
-        _ => return Err(SyntaxError::new("target: expecting target name")),
+        SExpr::Str(s, _) => Thing::Actual(s.to_string()),
+        SExpr::Id(i, _) => Thing::Pseudo(i.to_string()),
+        _ => return Err(syntax("target: expecting target name").at(sexpr[1].pos())),
     };
     let deps = match &sexpr[2] {
-        SExpr::List(l) => eval_stridlist(l)?,
-        _ => return Err(SyntaxError::new("target: expecting a list of dependencies")),
+        SExpr::List(l, _) => eval_stridlist(rec, l)?,
+        _ => return Err(syntax("target: expecting a list of dependencies").at(sexpr[2].pos())),
     };
     let cmds = match &sexpr[3] {
-        SExpr::List(l) => eval_strlist(l)?,
-        _ => return Err(SyntaxError::new("target: expecting a list of commands")),
+        SExpr::List(l, _) => eval_strlist(l)?,
+        _ => return Err(syntax("target: expecting a list of commands").at(sexpr[3].pos())),
+    };
+    let origin = Origin {
+        file: ctx.name().map(|n| n.to_string()),
+        lineno: pos.lineno,
     };
-    rec.add_rule(name, deps.into_iter(), cmds);
+    let options = eval_options(rec, &sexpr[4..])?;
+    rec.set_options(name.clone(), options);
+    rec.add_rule(name, deps.into_iter(), cmds, origin);
     Ok(())
 }
 
-fn eval_set(rec: &mut Recipe, sexpr: &[SExpr]) -> Result<(), SyntaxError> {
-    if sexpr.len() != 3 {
-        return Err(SyntaxError::new("set: expecting 2 list elements"));
+fn eval_pattern(rec: &mut Recipe, ctx: &Context, sexpr: &[SExpr], pos: Pos) -> Result<(), Error> {
+    if sexpr.len() < 4 {
+        return Err(syntax("pattern: expecting 4 or more list elements"));
     }
     let name = match &sexpr[1] {
-        SExpr::Str(s) => s,
-        _ => return Err(SyntaxError::new("set: expecting parameter name as string")),
+        SExpr::Str(s, _) if s.matches('%').count() == 1 => s.to_string(),
+        _ => {
+            return Err(
+                syntax("pattern: expecting target name as string with one '%'").at(sexpr[1].pos()),
+            )
+        }
+    };
+    let deps = match &sexpr[2] {
+        SExpr::List(l, _) => eval_stridlist(rec, l)?,
+        _ => return Err(syntax("pattern: expecting a list of dependencies").at(sexpr[2].pos())),
+    };
+    let cmds = match &sexpr[3] {
+        SExpr::List(l, _) => eval_strlist(l)?,
+        _ => return Err(syntax("pattern: expecting a list of commands").at(sexpr[3].pos())),
     };
-    let value = match &sexpr[2] {
-        SExpr::Str(s) => s,
-        _ => return Err(SyntaxError::new("set: expecting parameter value as string")),
+    let origin = Origin {
+        file: ctx.name().map(|n| n.to_string()),
+        lineno: pos.lineno,
     };
-    match rec.set_var(name, value) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(SyntaxError::new(format!("set: {:?}", e).as_ref())),
+    let options = eval_options(rec, &sexpr[4..])?;
+    rec.add_pattern(name, deps, cmds, options, origin);
+    Ok(())
+}
+
+// Parameters set with names that could never be expanded are most likely
+// typos.
+fn eval_name(sexpr: &SExpr) -> Result<&str, Error> {
+    match sexpr {
+        SExpr::Str(s, _) if valid_name(s) => Ok(s),
+        SExpr::Str(s, _) => {
+            Err(syntax(&format!("set: bad parameter name: {:?}", s)).at(sexpr.pos()))
+        }
+        _ => Err(syntax("set: expecting parameter name as string").at(sexpr.pos())),
     }
 }
 
-fn eval_list(rec: &mut Recipe, sexpr: &[SExpr]) -> Result<(), SyntaxError> {
-    // We two different "applications":
+// Values read from the environment are taken as they are, and so are the
+// values of parameters copied from them.
+fn literal(rec: &Recipe, sexpr: &SExpr) -> bool {
+    match sexpr {
+        SExpr::List(l, _) => match l.as_slice() {
+            [SExpr::Id(op, _), ..] if op == "env" => true,
+            [SExpr::Id(op, _), SExpr::Str(name, _)] if op == "var" => rec.is_literal(name),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn eval_set(rec: &mut Recipe, sexpr: &[SExpr]) -> Result<(), Error> {
+    if sexpr.len() != 3 {
+        return Err(syntax("set: expecting 2 list elements"));
+    }
+    let name = eval_name(&sexpr[1])?;
+    let value = eval_expr(rec, &sexpr[2])?;
+    if literal(rec, &sexpr[2]) {
+        rec.set_literal(name, value);
+    } else {
+        rec.set_value(name, value);
+    }
+    Ok(())
+}
+
+// Errors in the included file are added to `errs`. Only the errors of the
+// include form itself are returned.
+fn eval_include(
+    rec: &mut Recipe,
+    ctx: &mut Context,
+    sexpr: &[SExpr],
+    errs: &mut Errors,
+) -> Result<(), Error> {
+    if sexpr.len() != 2 {
+        return Err(syntax("include: expecting 1 list element"));
+    }
+    let path = match &sexpr[1] {
+        SExpr::Str(s, _) => ctx.resolve(s),
+        _ => return Err(syntax("include: expecting file name as string").at(sexpr[1].pos())),
+    };
+    let pos = sexpr[1].pos();
+    let name = path.display().to_string();
+    let canonical = match fs::canonicalize(&path) {
+        Ok(c) => c,
+        Err(e) => return Err(syntax(&format!("include: {}: {}", name, e)).at(pos)),
+    };
+    if ctx
+        .stack
+        .iter()
+        .any(|s| s.path.as_ref() == Some(&canonical))
+    {
+        let chain = ctx
+            .stack
+            .iter()
+            .map(|s| s.name.as_str())
+            .chain(std::iter::once(name.as_str()))
+            .collect::<Vec<&str>>()
+            .join(" -> ");
+        return Err(syntax(&format!("include loop: {}", chain)).at(pos));
+    }
+    let src = match fs::read_to_string(&canonical) {
+        Ok(s) => s,
+        Err(e) => return Err(syntax(&format!("include: {}: {}", name, e)).at(pos)),
+    };
+    ctx.stack.push(Source {
+        name,
+        path: Some(canonical),
+    });
+    let res = lex(&src)
+        .and_then(parse)
+        .and_then(|sexprs| eval_forms(rec, ctx, sexprs.into_iter()));
+    if let Err(e) = res {
+        errs.append(e.map(|e| e.in_file(ctx.name()).quoting(&src)));
+    }
+    ctx.stack.pop();
+    Ok(())
+}
+
+fn eval_list(
+    rec: &mut Recipe,
+    ctx: &mut Context,
+    sexpr: &[SExpr],
+    pos: Pos,
+    errs: &mut Errors,
+) -> Result<(), Error> {
+    // We have four different "applications":
     //   1. set
     //   2. target
+    //   3. pattern
+    //   4. include
     if sexpr.is_empty() {
-        return Err(SyntaxError::new("nil list"));
+        return Err(syntax("nil list"));
     }
     let id = match &sexpr[0] {
-        SExpr::Id(_id) => _id,
-        _ => return Err(SyntaxError::new("expecting identifier")),
+        SExpr::Id(_id, _) => _id,
+        _ => return Err(syntax("expecting identifier").at(sexpr[0].pos())),
     };
     match id.as_str() {
-        "target" => eval_target(rec, &sexpr),
-        "set" => eval_set(rec, &sexpr),
-        _ => Err(SyntaxError::new("unrecognized command")),
+        "target" => eval_target(rec, ctx, sexpr, pos),
+        "pattern" => eval_pattern(rec, ctx, sexpr, pos),
+        "set" => eval_set(rec, sexpr),
+        "include" => eval_include(rec, ctx, sexpr, errs),
+        _ => Err(syntax(&format!("unrecognized command: {}", id)).at(sexpr[0].pos())),
     }
 }
 
-pub fn eval<T>(sexprs: T) -> Result<Recipe, SyntaxError>
+// Evaluates all of the forms, even after errors, so that as many of them as
+// possible are reported at once.
+fn eval_forms<T>(rec: &mut Recipe, ctx: &mut Context, sexprs: T) -> Result<(), Errors>
 where
     T: Iterator<Item = SExpr>,
 {
-    let mut rec = Recipe::new();
+    let mut errs = Errors::new();
     for sexpr in sexprs {
-        match sexpr {
-            SExpr::List(l) => match eval_list(&mut rec, &l) {
-                Ok(_) => (),
-                Err(e) => return Err(e),
-            },
-            _ => return Err(SyntaxError::new("top-level expression not a list")),
+        let res = match sexpr {
+            SExpr::List(l, pos) => eval_list(rec, ctx, &l, pos, &mut errs).map_err(|e| e.at(pos)),
+            _ => Err(syntax("top-level expression not a list").at(sexpr.pos())),
         };
+        if let Err(e) = res {
+            errs.push(e);
+        }
     }
+    errs.or_ok(())
+}
+
+fn eval_top<T>(mut ctx: Context, mut rec: Recipe, sexprs: T) -> Result<Recipe, Errors>
+where
+    T: Iterator<Item = SExpr>,
+{
+    let name = ctx.name().map(|n| n.to_string());
+    let name = name.as_deref();
+    eval_forms(&mut rec, &mut ctx, sexprs).map_err(|e| e.map(|e| e.in_file(name)))?;
+    rec.apply_patterns();
+    // Cycles are easy to produce by accident, and building in any order would
+    // be wrong, so the whole recipe is rejected.
+    rec.check_cycles().map_err(|e| e.in_file(name))?;
     Ok(rec)
 }
 
+pub fn eval<T>(sexprs: T) -> Result<Recipe, Errors>
+where
+    T: Iterator<Item = SExpr>,
+{
+    eval_top(Context { stack: Vec::new() }, Recipe::new(), sexprs)
+}
+
+// Evaluates the contents of the file `name`. Included files are looked up
+// relative to `path`, or the current directory if there is none. Parameters in
+// `overrides` keep their values whatever the file sets them to.
+pub fn eval_file<T>(
+    sexprs: T,
+    name: &str,
+    path: Option<&Path>,
+    overrides: &[(String, String)],
+) -> Result<Recipe, Errors>
+where
+    T: Iterator<Item = SExpr>,
+{
+    let source = Source {
+        name: name.to_string(),
+        path: path.and_then(|p| fs::canonicalize(p).ok()),
+    };
+    let mut rec = Recipe::new();
+    for (name, value) in overrides.iter() {
+        rec.override_var(name, value);
+    }
+    eval_top(
+        Context {
+            stack: vec![source],
+        },
+        rec,
+        sexprs,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tempdir::TempDir;
+
+    fn target(name: &str, lineno: u64, deps: Vec<SExpr>) -> SExpr {
+        let p = Pos::new(lineno, 1);
+        SExpr::List(
+            vec![
+                SExpr::Id("target".to_string(), p),
+                SExpr::Str(name.to_string(), p),
+                SExpr::List(deps, p),
+                SExpr::List(vec![SExpr::Str("cmd".to_string(), p)], p),
+            ],
+            p,
+        )
+    }
 
     #[test]
     fn test_eval_target() {
-        let s = SExpr::List(vec![
-            SExpr::Id("target".to_string()),
-            SExpr::Str("name".to_string()),
-            SExpr::List(vec![
-                SExpr::Str("depstr".to_string()),
-                SExpr::Id("depid".to_string()),
-            ]),
-            SExpr::List(vec![SExpr::Str("cmd".to_string())]),
-        ]);
+        let p = Pos::default();
+        let s = target(
+            "name",
+            1,
+            vec![
+                SExpr::Str("depstr".to_string(), p),
+                SExpr::Id("depid".to_string(), p),
+            ],
+        );
         eval(vec![s].into_iter()).unwrap();
     }
+
+    #[test]
+    fn test_eval_lists() {
+        let src = r#"
+(set "SRCS" (list "main.c" "util.c" "README"))
+(set "CSRCS" (filter "%.c" (var "SRCS")))
+(set "OBJS" (map "%.c" "build/%.o" (var "CSRCS")))
+(set "N" (len (var "OBJS")))
+(set "FLAGS" (join "," (list "-a" "-b")))
+(target "prog" ((var "OBJS") "extra.o") ("ld -o $TARGET $OBJS $N $FLAGS"))
+"#;
+        let rec = eval(parse(lex(src).unwrap()).unwrap().into_iter()).unwrap();
+        let objs = vec!["build/main.o".to_string(), "build/util.o".to_string()];
+        assert_eq!(Some(&Value::List(objs)), rec.get_var("OBJS"));
+        assert_eq!(Some(&Value::Str("2".to_string())), rec.get_var("N"));
+        assert_eq!(Some(&Value::Str("-a,-b".to_string())), rec.get_var("FLAGS"));
+        assert!(rec.rule_exists(&Thing::Actual("build/util.o".to_string())));
+
+        let bad = r#"(set "X" (len (var "NOPE")))"#;
+        match eval(parse(lex(bad).unwrap()).unwrap().into_iter()) {
+            Ok(_) => panic!("unknown parameter not detected"),
+            Err(e) => assert!(e.to_string().contains("unknown parameter: NOPE")),
+        }
+    }
+
+    #[test]
+    fn test_eval_error_position() {
+        let src = "(set \"A\" \"a\")\n(target \"x\" (\"a\") \"cmd\")";
+        let e = match eval(parse(lex(src).unwrap()).unwrap().into_iter()) {
+            Ok(_) => panic!("bad commands not detected"),
+            Err(e) => e.into_iter().next().unwrap(),
+        };
+        assert_eq!(Kind::Syntax, e.kind());
+        assert_eq!(Some(Pos::new(2, 19)), e.pos());
+        assert_eq!(
+            "2:19: syntax error: target: expecting a list of commands\n    (target \"x\" (\"a\") \"cmd\")\n                      ^",
+            e.quoting(src).to_string()
+        );
+    }
+
+    #[test]
+    fn test_eval_all_errors() {
+        let src = r#"(set "A" (var "NOPE"))
+(bogus "x")
+(target "x" ("a") ("cmd") (set "A-B" "w"))
+(set "B")
+(set "1X" "v")"#;
+        let errs: Vec<(String, Option<Pos>)> =
+            match eval(parse(lex(src).unwrap()).unwrap().into_iter()) {
+                Ok(_) => panic!("errors not detected"),
+                Err(e) => e
+                    .into_iter()
+                    .map(|e| (e.msg().to_string(), e.pos()))
+                    .collect(),
+            };
+        assert_eq!(
+            vec![
+                (
+                    "var: unknown parameter: NOPE".to_string(),
+                    Some(Pos::new(1, 15))
+                ),
+                (
+                    "unrecognized command: bogus".to_string(),
+                    Some(Pos::new(2, 2))
+                ),
+                (
+                    "set: bad parameter name: \"A-B\"".to_string(),
+                    Some(Pos::new(3, 32))
+                ),
+                (
+                    "set: expecting 2 list elements".to_string(),
+                    Some(Pos::new(4, 1))
+                ),
+                (
+                    "set: bad parameter name: \"1X\"".to_string(),
+                    Some(Pos::new(5, 6))
+                ),
+            ],
+            errs
+        );
+    }
+
+    #[test]
+    fn test_eval_cycle() {
+        let p = Pos::default();
+        let s = vec![
+            target("a", 1, vec![SExpr::Str("b".to_string(), p)]),
+            target("b", 2, vec![SExpr::Str("c".to_string(), p)]),
+            target("c", 3, vec![SExpr::Str("a".to_string(), p)]),
+        ];
+        match eval(s.into_iter()) {
+            Ok(_) => panic!("cycle not detected"),
+            Err(e) => assert_eq!(
+                r#"syntax error: dependency cycle: "a" (line 1) -> "b" (line 2) -> "c" (line 3) -> "a""#,
+                e.to_string()
+            ),
+        }
+    }
+
+    #[test]
+    fn test_eval_overrides() {
+        let src = r#"(set "CC" "cc")
+(set "BUILD" "$CC -c")
+(set "SEARCH" (env "PATH"))
+(set "OTHER" (env "PMA_SURELY_UNSET" (list "a" "b")))"#;
+        let overrides = vec![
+            ("CC".to_string(), "clang".to_string()),
+            ("PREFIX".to_string(), "$HOME/.local".to_string()),
+        ];
+        let sexprs = parse(lex(src).unwrap()).unwrap();
+        let rec = eval_file(sexprs.into_iter(), "x.pma", None, &overrides).unwrap();
+        let var = |name: &str| rec.get_var(name).unwrap().to_string();
+        assert_eq!("clang", var("CC"));
+        assert_eq!("clang -c", var("BUILD"));
+        assert_eq!(std::env::var("PATH").unwrap(), var("SEARCH"));
+        assert_eq!("a b", var("OTHER"));
+        assert_eq!("$HOME/.local", var("PREFIX"));
+
+        let src = r#"(set "HOME" (env "PMA_SURELY_UNSET"))"#;
+        match eval(parse(lex(src).unwrap()).unwrap().into_iter()) {
+            Ok(_) => panic!("unset environment variable not detected"),
+            Err(e) => assert!(e.to_string().contains("env: PMA_SURELY_UNSET")),
+        }
+    }
+
+    #[test]
+    fn test_eval_include() {
+        let dir = TempDir::new("include");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let top = dir.join("top.pma");
+        fs::write(&top, r#"(include "sub/one.pma")"#).unwrap();
+        fs::write(dir.join("sub/one.pma"), r#"(include "two.pma")"#).unwrap();
+        fs::write(dir.join("sub/two.pma"), r#"(set "X" "two")"#).unwrap();
+
+        let p = Pos::default();
+        let include = |path: &str| {
+            SExpr::List(
+                vec![
+                    SExpr::Id("include".to_string(), p),
+                    SExpr::Str(path.to_string(), p),
+                ],
+                p,
+            )
+        };
+        let name = top.display().to_string();
+        let rec = eval_file(
+            vec![include("sub/one.pma")].into_iter(),
+            &name,
+            Some(&top),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(Some(&Value::Str("two".to_string())), rec.get_var("X"));
+
+        fs::write(dir.join("sub/two.pma"), r#"(include "one.pma")"#).unwrap();
+        match eval_file(
+            vec![include("sub/one.pma")].into_iter(),
+            &name,
+            Some(&top),
+            &[],
+        ) {
+            Ok(_) => panic!("include loop not detected"),
+            Err(e) => assert!(e.to_string().contains("include loop")),
+        }
+    }
 }
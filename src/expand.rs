@@ -0,0 +1,263 @@
+use std::path::Path;
+
+use crate::recipe::match_pattern;
+
+fn is_start(c: char) -> bool {
+    c == '_' || c.is_ascii_alphabetic()
+}
+
+fn is_name(c: char) -> bool {
+    c == '_' || c.is_ascii_alphanumeric()
+}
+
+// Checks that `name` can be used as a parameter name.
+pub fn valid_name(name: &str) -> bool {
+    name.starts_with(is_start) && name.chars().all(is_name)
+}
+
+// The directory part of a path, "." if there is none.
+pub fn dir(path: &str) -> String {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().to_string(),
+        _ => ".".to_string(),
+    }
+}
+
+// The file name part of a path.
+pub fn base(path: &str) -> String {
+    match Path::new(path).file_name() {
+        Some(base) => base.to_string_lossy().to_string(),
+        None => path.to_string(),
+    }
+}
+
+// Replaces the suffix `from` of `word` with `to`. With a "%" in `from`, the
+// whole word has to match it, and the stem replaces the "%" in `to`.
+fn substitute(word: &str, from: &str, to: &str) -> String {
+    if from.contains('%') {
+        return match match_pattern(from, word) {
+            Some(stem) => to.replacen('%', stem, 1),
+            None => word.to_string(),
+        };
+    }
+    match word.strip_suffix(from) {
+        Some(rest) => format!("{}{}", rest, to),
+        None => word.to_string(),
+    }
+}
+
+// Finds the brace closing one that was just opened.
+fn closing(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// The value of a parameter. Values are expanded in turn when inserted, while
+// literal ones, which come from outside of the recipe, are taken as they are.
+pub enum Param {
+    Value(String),
+    Literal(String),
+}
+
+struct Expander<'a, F> {
+    lookup: &'a F,
+    // Whether the result is to be expanded again, so that literal values have
+    // to be escaped.
+    escape: bool,
+    // The parameters whose values are being expanded, to catch the ones
+    // referring to themselves.
+    active: Vec<String>,
+}
+
+impl<F> Expander<'_, F>
+where
+    F: Fn(&str) -> Option<Param>,
+{
+    fn expand(&mut self, s: &str) -> Result<String, String> {
+        let mut ret = String::new();
+        let mut rest = s;
+        while let Some(i) = rest.find('$') {
+            ret.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(r) = rest.strip_prefix('$') {
+                ret.push('$');
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix('{') {
+                let end = match closing(r) {
+                    Some(end) => end,
+                    None => return Err(format!("Unterminated \"${{\" in expansion: {}", s)),
+                };
+                ret.push_str(&self.braced(&r[..end])?);
+                rest = &r[end + 1..];
+            } else if rest.starts_with(is_start) {
+                let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+                ret.push_str(&self.value(&rest[..end])?);
+                rest = &rest[end..];
+            } else {
+                // Anything else is left to the shell.
+                ret.push('$');
+            }
+        }
+        ret.push_str(rest);
+        Ok(ret)
+    }
+
+    // Values are expanded in turn, which lets parameters set with "$$" refer
+    // to automatic parameters only known when expanding commands.
+    fn recurse(&mut self, name: &str, value: &str) -> Result<String, String> {
+        if self.active.iter().any(|a| a == name) {
+            return Err(format!("Parameter refers to itself in expansion: {}", name));
+        }
+        self.active.push(name.to_string());
+        let ret = self.expand(value);
+        self.active.pop();
+        ret
+    }
+
+    fn insert(&mut self, name: &str, param: Param) -> Result<String, String> {
+        match param {
+            Param::Value(value) => self.recurse(name, &value),
+            Param::Literal(value) if self.escape => Ok(value.replace('$', "$$")),
+            Param::Literal(value) => Ok(value),
+        }
+    }
+
+    fn value(&mut self, name: &str) -> Result<String, String> {
+        match (self.lookup)(name) {
+            Some(param) => self.insert(name, param),
+            None => Err(format!("Unrecognized parameter in expansion: {}", name)),
+        }
+    }
+
+    // Expands the inside of "${...}".
+    fn braced(&mut self, inner: &str) -> Result<String, String> {
+        let end = inner.find(|c| !is_name(c)).unwrap_or(inner.len());
+        let (name, modifier) = inner.split_at(end);
+        let bad = || Err(format!("Bad parameter name in expansion: ${{{}}}", inner));
+        if !valid_name(name) {
+            return bad();
+        }
+        let modifier = match modifier.strip_prefix(':') {
+            Some(m) => m,
+            None if modifier.is_empty() => return self.value(name),
+            None => return bad(),
+        };
+        if let Some(default) = modifier.strip_prefix('-') {
+            return match (self.lookup)(name) {
+                Some(Param::Value(v) | Param::Literal(v)) if v.is_empty() => self.expand(default),
+                Some(param) => self.insert(name, param),
+                None => self.expand(default),
+            };
+        }
+        let value = self.value(name)?;
+        let words = value.split_whitespace();
+        let words: Vec<String> = match modifier {
+            "dir" => words.map(dir).collect(),
+            "base" => words.map(base).collect(),
+            _ => match modifier.split_once('=') {
+                Some((from, to)) if !from.is_empty() => {
+                    let (from, to) = (self.expand(from)?, self.expand(to)?);
+                    words.map(|w| substitute(w, &from, &to)).collect()
+                }
+                _ => return Err(format!("Unknown modifier in expansion: ${{{}}}", inner)),
+            },
+        };
+        Ok(words.join(" "))
+    }
+}
+
+// Expands the parameters in `s`, looking their values up with `lookup`. A
+// "$$" stands for a plain "$".
+pub fn expand<F>(s: &str, lookup: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<Param>,
+{
+    Expander {
+        lookup,
+        escape: false,
+        active: Vec::new(),
+    }
+    .expand(s)
+}
+
+// Expands `s` like `expand` into the value of a parameter, which is expanded
+// again when inserted.
+pub fn expand_value<F>(s: &str, lookup: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<Param>,
+{
+    Expander {
+        lookup,
+        escape: true,
+        active: Vec::new(),
+    }
+    .expand(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<Param> {
+        if name == "HOME" {
+            return Some(Param::Literal("/home/$USER".to_string()));
+        }
+        let value = match name {
+            "CC" => "cc",
+            "ARCH" => "x86",
+            "ARCH64" => "x86_64",
+            "SRCS" => "src/a.c  b.c c.h",
+            "EMPTY" => "",
+            "BUILD" => "$CC -o $TARGET",
+            "TARGET" => "prog",
+            "LOOP" => "${LOOP:base}",
+            _ => return None,
+        };
+        Some(Param::Value(value.to_string()))
+    }
+
+    #[test]
+    fn test_expand() {
+        let ok = |s: &str| expand(s, &lookup).unwrap();
+        assert_eq!("x86-cc", ok("$ARCH-$CC"));
+        assert_eq!("x86_64cc x86cc", ok("$ARCH64$CC ${ARCH}cc"));
+        assert_eq!("cc -o prog -c", ok("$BUILD -c"));
+        assert_eq!("$CC $1 $ $(pwd)", ok("$$CC $1 $ $(pwd)"));
+        assert_eq!("cc gcc", ok("${CC:-gcc} ${CLANG:-gcc}"));
+        assert_eq!("x86-cc", ok("${EMPTY:-${ARCH}-$CC}"));
+        assert_eq!("src/a.o b.o c.h", ok("${SRCS:.c=.o}"));
+        assert_eq!("obj/a.o b.c c.h", ok("${SRCS:src/%.c=obj/%.o}"));
+        assert_eq!("src . .", ok("${SRCS:dir}"));
+        assert_eq!("a.c b.c c.h", ok("${SRCS:base}"));
+        assert_eq!("/home/$USER/bin /home", ok("$HOME/bin ${HOME:dir}"));
+        assert_eq!(
+            "/home/$$USER/bin",
+            expand_value("$HOME/bin", &lookup).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let err = |s: &str| expand(s, &lookup).unwrap_err();
+        assert_eq!("Unrecognized parameter in expansion: NOPE", err("$NOPE"));
+        assert_eq!(
+            "Unrecognized parameter in expansion: NOPE",
+            err("${NOPE:dir}")
+        );
+        assert_eq!("Unterminated \"${\" in expansion: ${CC", err("${CC"));
+        assert_eq!("Bad parameter name in expansion: ${1X}", err("${1X}"));
+        assert_eq!("Unknown modifier in expansion: ${CC:up}", err("${CC:up}"));
+        assert_eq!(
+            "Parameter refers to itself in expansion: LOOP",
+            err("$LOOP")
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::Write;
+
+use crate::recipe::Thing;
+
+// A snapshot of the part of the dependency graph reachable from some targets,
+// with edges pointing from targets to their dependencies.
+#[derive(Debug, PartialEq)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Node {
+    pub thing: Thing,
+    pub rule: bool,
+    pub stale: bool,
+}
+
+impl Node {
+    fn name(&self) -> &str {
+        match &self.thing {
+            Thing::Actual(s) | Thing::Pseudo(s) => s,
+        }
+    }
+
+    fn kind(&self) -> &str {
+        match &self.thing {
+            Thing::Actual(_) => "actual",
+            Thing::Pseudo(_) => "pseudo",
+        }
+    }
+}
+
+// Quotes `s` as a string usable both in JSON and DOT.
+fn quote(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(ret, "\\u{:04x}", c as u32).unwrap(),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+impl Graph {
+    // Actual things are boxes and pseudo things ellipses. Things without a
+    // rule are dashed and stale things red. The same information is also
+    // given as the extra attributes "kind", "rule" and "stale".
+    pub fn to_dot(&self) -> String {
+        let mut ret = String::from("digraph pma {\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let shape = match node.thing {
+                Thing::Actual(_) => "box",
+                Thing::Pseudo(_) => "ellipse",
+            };
+            let style = if node.rule { "solid" } else { "dashed" };
+            let color = if node.stale { "red" } else { "black" };
+            writeln!(
+                ret,
+                "    n{} [label={}, shape={}, style={}, color={}, kind={}, rule={}, stale={}];",
+                i,
+                quote(node.name()),
+                shape,
+                style,
+                color,
+                node.kind(),
+                node.rule,
+                node.stale
+            )
+            .unwrap();
+        }
+        for (from, to) in self.edges.iter() {
+            writeln!(ret, "    n{} -> n{};", from, to).unwrap();
+        }
+        ret.push('}');
+        ret
+    }
+
+    pub fn to_json(&self) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                format!(
+                    "{{\"id\":{},\"name\":{},\"kind\":\"{}\",\"rule\":{},\"stale\":{}}}",
+                    i,
+                    quote(node.name()),
+                    node.kind(),
+                    node.rule,
+                    node.stale
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        let edges = self
+            .edges
+            .iter()
+            .map(|(from, to)| format!("{{\"from\":{},\"to\":{}}}", from, to))
+            .collect::<Vec<String>>()
+            .join(",");
+        format!("{{\"nodes\":[{}],\"edges\":[{}]}}", nodes, edges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> Graph {
+        Graph {
+            nodes: vec![
+                Node {
+                    thing: Thing::Pseudo("all".to_string()),
+                    rule: true,
+                    stale: true,
+                },
+                Node {
+                    thing: Thing::Actual("a \"b\".c".to_string()),
+                    rule: false,
+                    stale: false,
+                },
+            ],
+            edges: vec![(0, 1)],
+        }
+    }
+
+    #[test]
+    fn test_export_dot() {
+        assert_eq!(
+            r#"digraph pma {
+    n0 [label="all", shape=ellipse, style=solid, color=red, kind=pseudo, rule=true, stale=true];
+    n1 [label="a \"b\".c", shape=box, style=dashed, color=black, kind=actual, rule=false, stale=false];
+    n0 -> n1;
+}"#,
+            graph().to_dot()
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        assert_eq!(
+            r#"{"nodes":[{"id":0,"name":"all","kind":"pseudo","rule":true,"stale":true},{"id":1,"name":"a \"b\".c","kind":"actual","rule":false,"stale":false}],"edges":[{"from":0,"to":1}]}"#,
+            graph().to_json()
+        );
+    }
+}
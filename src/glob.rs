@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::Path;
+
+// Characters making a path component a pattern instead of a plain name.
+const SPECIAL: [char; 3] = ['*', '?', '['];
+
+// Character ranges of a bracket expression, single characters as (c, c).
+type Ranges = Vec<(char, char)>;
+
+// Matches `name` against a shell-style pattern with `*`, `?` and bracket
+// expressions like `[a-z]` or `[!0-9]`.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_from(&pattern, &name)
+}
+
+fn match_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| match_from(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && match_from(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), bracket(&pattern[1..])) {
+            (Some(&c), Some((set, negated, len))) => {
+                set.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated
+                    && match_from(&pattern[len + 1..], &name[1..])
+            }
+            (Some(&c), None) => c == '[' && match_from(&pattern[1..], &name[1..]),
+            (None, _) => false,
+        },
+        Some(&c) => name.first() == Some(&c) && match_from(&pattern[1..], &name[1..]),
+    }
+}
+
+// Parses a bracket expression following the opening `[`. Returns the ranges,
+// whether the expression is negated, and its length including the closing `]`.
+// An unterminated expression gives `None` and the `[` is taken literally.
+fn bracket(pattern: &[char]) -> Option<(Ranges, bool, usize)> {
+    let negated = matches!(pattern.first(), Some('!') | Some('^'));
+    let mut i = if negated { 1 } else { 0 };
+    let mut set = Vec::new();
+    // A `]` right at the start is part of the set.
+    let start = i;
+    while i < pattern.len() {
+        let c = pattern[i];
+        if c == ']' && i > start {
+            return Some((set, negated, i + 1));
+        }
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            set.push((c, pattern[i + 2]));
+            i += 3;
+        } else {
+            set.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+// Returns the existing paths matching `pattern` in sorted order. Wildcards do
+// not match "/", and only match a leading "." when the pattern component
+// starts with one.
+pub fn glob(pattern: &str) -> Vec<String> {
+    let (mut found, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    for component in rest.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for prefix in found.iter() {
+            if !component.contains(SPECIAL) {
+                next.push(format!("{}{}", prefix, component));
+                continue;
+            }
+            let dir = if prefix.is_empty() { "." } else { prefix };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if matches(component, &name) {
+                    next.push(format!("{}{}", prefix, name));
+                }
+            }
+        }
+        found = next.into_iter().map(|p| p + "/").collect();
+    }
+    let mut ret: Vec<String> = found
+        .into_iter()
+        .map(|p| p.trim_end_matches('/').to_string())
+        .filter(|p| !p.is_empty() && Path::new(p).exists())
+        .collect();
+    ret.sort();
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(matches("*.c", "foo.c"));
+        assert!(matches("*.c", ".c"));
+        assert!(!matches("*.c", "foo.h"));
+        assert!(matches("f?o*", "foo_main.c"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[x", "[x"));
+        assert!(!matches("a", "ab"));
+    }
+
+    #[test]
+    fn test_glob_paths() {
+        let dir = TempDir::new("glob");
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::create_dir_all(dir.join("a")).unwrap();
+        for name in ["b/2.c", "b/1.c", "a/x.c", "a/x.h", "a/.hidden.c"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let base = dir.display().to_string();
+        let got: Vec<String> = glob(&format!("{}/*/*.c", base))
+            .into_iter()
+            .map(|p| p[base.len()..].to_string())
+            .collect();
+        assert_eq!(vec!["/a/x.c", "/b/1.c", "/b/2.c"], got);
+        assert!(glob(&format!("{}/nothing/*.c", base)).is_empty());
+    }
+}
@@ -49,6 +49,55 @@ impl<T> DAG<T> {
             data: self.edges[from].to_vec(),
         })
     }
+
+    // Returns the nodes of some cycle with the first node repeated at the end,
+    // or `None` if the graph really is acyclic.
+    pub fn find_cycle(&self) -> Option<Vec<GraphIndex>> {
+        let mut state = vec![Visit::New; self.data.len()];
+        let mut path = Vec::new();
+        for i in 0..self.data.len() {
+            if let Some(cycle) = self.cycle_from(i, &mut state, &mut path) {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    fn cycle_from(
+        &self,
+        from: GraphIndex,
+        state: &mut Vec<Visit>,
+        path: &mut Vec<GraphIndex>,
+    ) -> Option<Vec<GraphIndex>> {
+        match state[from] {
+            Visit::Done => return None,
+            Visit::Active => {
+                // `from` is on the current path, so we came around to it.
+                let start = path.iter().position(|&i| i == from).unwrap();
+                let mut cycle = path[start..].to_vec();
+                cycle.push(from);
+                return Some(cycle);
+            }
+            Visit::New => (),
+        }
+        state[from] = Visit::Active;
+        path.push(from);
+        for &to in self.edges[from].iter() {
+            if let Some(cycle) = self.cycle_from(to, state, path) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        state[from] = Visit::Done;
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Visit {
+    New,
+    Active,
+    Done,
 }
 
 #[derive(Debug, PartialEq)]
@@ -97,4 +146,18 @@ mod tests {
         assert_eq!(Some(Successors { data: vec![] }), dag.successors(i1));
         assert_eq!(Some(Successors { data: vec![i1, i2] }), dag.successors(i0));
     }
+
+    #[test]
+    fn test_graph_dag_cycle() {
+        let mut dag: DAG<u8> = DAG::new();
+        let i0 = dag.node(0);
+        let i1 = dag.node(1);
+        let i2 = dag.node(2);
+        dag.connect(i0, i1);
+        dag.connect(i1, i2);
+        assert_eq!(None, dag.find_cycle());
+
+        dag.connect(i2, i1);
+        assert_eq!(Some(vec![i1, i2, i1]), dag.find_cycle());
+    }
 }
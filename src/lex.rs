@@ -1,44 +1,18 @@
-use std::error;
-use std::fmt;
-
+use crate::error::{Error, Errors, Kind};
+use crate::sexpr::Pos;
 use crate::tokens::{Token, Tokens};
 
-#[derive(Debug, Clone)]
-pub struct LexError {
-    msg: String,
-    lineno: u64,
-    col: u64,
-}
-
-impl fmt::Display for LexError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}: lex error: {}", self.lineno, self.col, self.msg)
-    }
-}
-
-impl error::Error for LexError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        None
-    }
-}
-
-impl LexError {
-    pub fn new(msg: &str, lineno: u64, col: u64) -> LexError {
-        LexError {
-            msg: msg.to_string(),
-            lineno,
-            col,
-        }
-    }
+fn lex_error(msg: &str, lineno: u64, col: u64) -> Error {
+    Error::new(Kind::Lex, msg).at(Pos::new(lineno, col))
 }
 
 fn lex_string(
     it: &mut std::iter::Peekable<std::str::Chars>,
     lineno: &mut u64,
     col: &mut u64,
-) -> Result<String, LexError> {
+) -> Result<String, Error> {
     let mut res = String::new();
-    let start = *col;
+    let (startline, start) = (*lineno, *col);
     let mut backslashed = false;
     let mut end = false;
     while let Some(&c) = it.peek() {
@@ -60,7 +34,7 @@ fn lex_string(
             }
             '\n' => {
                 *lineno += 1;
-                *col = 1;
+                *col = 0;
                 res.push(c);
             }
             _ => res.push(c),
@@ -71,13 +45,16 @@ fn lex_string(
         }
     }
     if !end {
-        return Err(LexError::new("unterminated string", *lineno, start));
+        return Err(lex_error("unterminated string", startline, start));
     }
     Ok(res)
 }
 
-pub fn lex(src: &str) -> Result<Tokens, LexError> {
+// Lexes all of `src`. Unexpected characters are reported and skipped up to the
+// next token, so that all of them are found in one go.
+pub fn lex(src: &str) -> Result<Tokens, Errors> {
     let mut res = Tokens::new();
+    let mut errs = Errors::new();
     let mut it = src.chars().peekable();
     let mut lineno = 1;
     let mut col = 0;
@@ -90,6 +67,7 @@ pub fn lex(src: &str) -> Result<Tokens, LexError> {
             if c == '\n' {
                 fastforward = false;
                 lineno += 1;
+                col = 0;
             }
             continue;
         }
@@ -118,17 +96,27 @@ pub fn lex(src: &str) -> Result<Tokens, LexError> {
                 res.push(Token::Id(id), lineno, start);
             }
             '"' => {
-                let start = col;
+                // Strings may span lines, and are placed where they start.
+                let (startline, start) = (lineno, col);
                 match lex_string(&mut it, &mut lineno, &mut col) {
-                    Ok(s) => res.push(Token::Str(s), lineno, start),
-                    Err(e) => return Err(e),
+                    Ok(s) => res.push(Token::Str(s), startline, start),
+                    Err(e) => errs.push(e),
                 }
             }
 
-            _ => return Err(LexError::new("unexpected character", lineno, col)),
+            _ => {
+                errs.push(lex_error("unexpected character", lineno, col));
+                while let Some(&cc) = it.peek() {
+                    match cc {
+                        '(' | ')' | '"' | '#' | ' ' | '\t' | '\n' => break,
+                        _ => col += 1,
+                    }
+                    it.next();
+                }
+            }
         }
     }
-    Ok(res)
+    errs.or_ok(res)
 }
 
 #[cfg(test)]
@@ -201,4 +189,40 @@ mod tests {
         assert_eq!(Token::Id("two".to_string()), toks[2].0);
         assert_eq!(Token::RParen, toks[3].0);
     }
+
+    #[test]
+    fn test_lex_comment_positions() {
+        let toks: Vec<(Token, u64, u64)> = lex("# comment\n(a)").unwrap().collect();
+        assert_eq!((Token::LParen, 2, 1), toks[0]);
+        assert_eq!((Token::Id("a".to_string()), 2, 2), toks[1]);
+    }
+
+    #[test]
+    fn test_lex_multiline_string() {
+        let e = lex("(a)\n \"b\nc").unwrap_err().into_iter().next().unwrap();
+        assert_eq!(Some(Pos::new(2, 2)), e.pos());
+        let toks: Vec<(Token, u64, u64)> = lex("(set \"X\" \"a\nbc\" x)").unwrap().collect();
+        assert_eq!((Token::Str("a\nbc".to_string()), 1, 10), toks[3]);
+        assert_eq!((Token::Id("x".to_string()), 2, 5), toks[4]);
+    }
+
+    #[test]
+    fn test_lex_errors() {
+        let src = "(one @@ two)\n(three %)\n\"four";
+        let errs: Vec<(String, Option<Pos>)> = match lex(src) {
+            Ok(_) => panic!("errors not detected"),
+            Err(e) => e
+                .into_iter()
+                .map(|e| (e.msg().to_string(), e.pos()))
+                .collect(),
+        };
+        assert_eq!(
+            vec![
+                ("unexpected character".to_string(), Some(Pos::new(1, 6))),
+                ("unexpected character".to_string(), Some(Pos::new(2, 8))),
+                ("unterminated string".to_string(), Some(Pos::new(3, 1))),
+            ],
+            errs
+        );
+    }
 }
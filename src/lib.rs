@@ -14,10 +14,22 @@ macro_rules! condln {
     }
 }
 
+pub mod cli;
+pub mod depfile;
+pub mod error;
 pub mod eval;
+pub mod expand;
+pub mod export;
+pub mod glob;
 pub mod graph;
 pub mod lex;
 pub mod parse;
 pub mod recipe;
+pub mod run;
+pub mod sched;
 pub mod sexpr;
+pub mod signals;
+pub mod state;
+#[cfg(test)]
+mod tempdir;
 pub mod tokens;
@@ -1,30 +1,67 @@
 use std::collections::VecDeque;
-use std::process::{exit, Command};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{
     env, fs,
-    io::{self, Read},
+    io::{self, IsTerminal, Read},
+    path::Path,
 };
 
-use pma::eval::eval;
+use pma::cli::{parse_args, Action, GraphFormat, USAGE};
+use pma::error::Errors;
+use pma::eval::eval_file;
 use pma::lex::lex;
 use pma::parse::parse;
 use pma::recipe::{Recipe, SearchResult, Thing};
+use pma::run::{self, Mode};
+use pma::signals;
+use pma::state::State;
 use pma::{condln, Verbosity};
 
-fn get_input() -> io::Result<String> {
-    let mut buf = String::new();
-    io::stdin().read_to_string(&mut buf)?;
-    Ok(buf)
+// Build files looked up in the current directory when none is given.
+const DEFAULT_FILES: [&str; 2] = ["build.pma", "Pmafile"];
+
+// Build state kept in the current directory between runs.
+const STATE_FILE: &str = ".pma-state";
+
+// Name of the input used in messages when reading from stdin.
+const STDIN_NAME: &str = "<stdin>";
+
+// Picks the build file: the one given with `-f`, the first of the default
+// files present, or stdin if it is not a terminal. "-" means stdin.
+fn find_input(file: Option<String>) -> Option<String> {
+    if file.is_some() {
+        return file;
+    }
+    for name in DEFAULT_FILES.iter() {
+        if Path::new(name).is_file() {
+            return Some(name.to_string());
+        }
+    }
+    if io::stdin().is_terminal() {
+        return None;
+    }
+    Some("-".to_string())
 }
 
-fn determine_targets(rec: &Recipe, names: VecDeque<String>) -> Option<VecDeque<Thing>> {
+fn get_input(file: &str) -> io::Result<String> {
+    if file == "-" {
+        let mut buf = String::new();
+        io::stdin().read_to_string(&mut buf)?;
+        return Ok(buf);
+    }
+    fs::read_to_string(file)
+}
+
+fn determine_targets(rec: &mut Recipe, names: VecDeque<String>) -> Option<VecDeque<Thing>> {
     let mut inerr = 0u64;
     let mut queue: VecDeque<Thing> = VecDeque::new();
 
     // The first evaluated rule is the default target. No default rule => no
     // valid targets.
     let def = rec.get_default();
-    if def == None {
+    if def.is_none() {
         eprintln!("No targets.");
         return None;
     }
@@ -39,6 +76,8 @@ fn determine_targets(rec: &Recipe, names: VecDeque<String>) -> Option<VecDeque<T
             queue.push_back(act);
         } else if rec.rule_exists(&pse) {
             queue.push_back(pse);
+        } else if rec.add_pattern_rules(&act) {
+            queue.push_back(act);
         } else {
             eprintln!("Target does not exist: {:?}", name);
             inerr += 1;
@@ -50,99 +89,178 @@ fn determine_targets(rec: &Recipe, names: VecDeque<String>) -> Option<VecDeque<T
     Some(queue)
 }
 
-fn run_target(rec: &Recipe, thing: Thing) -> bool {
+// Targets left unbuilt when keeping going after failures.
+#[derive(Debug, Default)]
+struct Summary {
+    failed: Vec<Thing>,
+    skipped: Vec<Thing>,
+}
+
+impl Summary {
+    fn is_empty(&self) -> bool {
+        self.failed.is_empty() && self.skipped.is_empty()
+    }
+
+    fn print(&self) {
+        eprintln!("Failed targets:");
+        for thing in self.failed.iter() {
+            eprintln!("    {}", thing);
+        }
+        eprintln!("Skipped targets:");
+        for thing in self.skipped.iter() {
+            eprintln!("    {}", thing);
+        }
+    }
+}
+
+fn regenerate(target: &Thing, dep: &Thing) -> Result<bool, String> {
+    // We have four possibilities here:
+    //
+    //    1. Pseudo target, pseudo dependency => always regenerate
+    //    2. Pseudo target, real dependency => always regenerate
+    //    3. Actual target, pseudo dependency => always regenerate
+    //    4. Actual target, actual dependency => compare modified times
+    //
+    match (target, dep) {
+        (Thing::Pseudo(_), _) | (_, Thing::Pseudo(_)) => Ok(true),
+        (Thing::Actual(fn_target), Thing::Actual(fn_dep)) => {
+            let mod_target = match fs::metadata(fn_target) {
+                Err(_) => return Ok(true), // target probably does not exist
+                Ok(md) => md.modified().unwrap(),
+            };
+            let mod_dep = match fs::metadata(fn_dep) {
+                Err(_) => return Ok(true), // dep probably does not exist
+                Ok(md) => md.modified().unwrap(),
+            };
+            Ok(mod_dep > mod_target)
+        }
+    }
+}
+
+fn run_targets(
+    rec: &Recipe,
+    targets: &[Thing],
+    mode: Mode,
+    log_dir: Option<&Path>,
+    stale: Arc<AtomicBool>,
+    summary: &mut Summary,
+) -> bool {
     condln!(
         rec.get_verbosity(),
         Verbosity::Verbose,
         "[] evaluating {:?}",
-        thing
-    );
-    let res = rec.evaluate(
-        &thing,
-        Box::new(&|rec: &Recipe, cmd: &String| {
-            condln!(rec.get_verbosity(), Verbosity::Verbose, "[cmd] {}", cmd);
-            match Command::new("sh").arg("-c").arg(cmd.clone()).status() {
-                Err(e) => {
-                    eprintln!("Error when executing {}: {:?}", cmd, e);
-                    false
-                }
-                Ok(ec) => ec.code() == Some(0),
-            }
-        }),
-        Box::new(&|target: &Thing, dep: &Thing| -> Result<bool, String> {
-            // We have four possibilities here:
-            //
-            //    1. Pseudo target, pseudo dependency => always regenerate
-            //    2. Pseudo target, real dependency => always regenerate
-            //    3. Actual target, pseudo dependency => always regenerate
-            //    4. Actual target, actual dependency => compare modified times
-            //
-            match (target, dep) {
-                (&Thing::Pseudo(_), _) | (_, &Thing::Pseudo(_)) => Ok(true),
-                (&Thing::Actual(ref fn_target), &Thing::Actual(ref fn_dep)) => {
-                    let mod_target = match fs::metadata(fn_target) {
-                        Err(_) => return Ok(true), // target probably does not exist
-                        Ok(md) => md.modified().unwrap(),
-                    };
-                    let mod_dep = match fs::metadata(fn_dep) {
-                        Err(_) => return Ok(true), // dep probably does not exist
-                        Ok(md) => md.modified().unwrap(),
-                    };
-                    Ok(mod_dep > mod_target)
-                }
-            }
-        }),
+        targets
     );
+    let runner = run::runner(mode, log_dir, stale);
+    let res = rec.evaluate(targets, runner, Box::new(regenerate));
     match res {
         SearchResult::Cancelled => {
-            println!("Build in error: {:?}", thing);
+            println!("Build in error: {:?}", targets);
+            false
+        }
+        SearchResult::Incomplete { failed, skipped } => {
+            println!("Build in error: {:?}", targets);
+            *summary = Summary { failed, skipped };
             false
         }
         SearchResult::Ok => {
-            println!("Build successful: {:?}", thing);
+            if mode != Mode::Question {
+                println!("Build successful: {:?}", targets);
+            }
             true
         }
     }
 }
 
 fn main() {
-    let mut targets: VecDeque<String> = env::args().collect();
-
-    let v = match std::env::var("PMA_VERBOSE") {
-        Err(_) => Verbosity::Minimal,
-        Ok(val) => match val.as_str() {
-            "minimal" => Verbosity::Minimal,
-            "verbose" => Verbosity::Verbose,
-            "debug" => Verbosity::Debug,
-            _ => Verbosity::Minimal,
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Try 'pma --help' for more information.");
+            exit(7);
+        }
+    };
+    match opts.action {
+        Action::Help => {
+            println!("{}", USAGE);
+            exit(0);
+        }
+        Action::Version => {
+            println!("pma {}", env!("CARGO_PKG_VERSION"));
+            exit(0);
+        }
+        Action::Build => (),
+    }
+
+    // Command-line verbosity wins over the environment.
+    let v = match opts.verbosity {
+        Some(v) => v,
+        None => match std::env::var("PMA_VERBOSE") {
+            Err(_) => Verbosity::Minimal,
+            Ok(val) => match val.as_str() {
+                "minimal" => Verbosity::Minimal,
+                "verbose" => Verbosity::Verbose,
+                "debug" => Verbosity::Debug,
+                _ => Verbosity::Minimal,
+            },
         },
     };
 
-    targets.pop_front();
-    let input = match get_input() {
+    if let Some(dir) = opts.directory {
+        if let Err(e) = env::set_current_dir(&dir) {
+            eprintln!("{}: unable to change directory: {}", dir, e);
+            exit(1);
+        }
+        condln!(v, Verbosity::Verbose, "[] entered directory {:?}", dir);
+    }
+
+    let file = match find_input(opts.file) {
+        Some(f) => f,
+        None => {
+            eprintln!(
+                "No build file found ({}), use -f - to read from stdin.",
+                DEFAULT_FILES.join(", ")
+            );
+            exit(1);
+        }
+    };
+    let name = if file == "-" { STDIN_NAME } else { &file };
+    let input = match get_input(&file) {
         Ok(s) => s,
         Err(s) => {
-            eprintln!("unable to read input: {:?}", s);
+            eprintln!("{}: unable to read input: {}", name, s);
             exit(1);
         }
     };
+    // Errors are reported along with the line they are on.
+    let report = |errs: Errors| {
+        for e in errs {
+            eprintln!("{}", e.in_file(Some(name)).quoting(&input));
+        }
+    };
     let toks = match lex(&input) {
         Err(e) => {
-            eprintln!("{:?}", e);
+            report(e);
             exit(2)
         }
         Ok(t) => t,
     };
     let sexprs = match parse(toks) {
         Err(e) => {
-            eprintln!("{:?}", e);
+            report(e);
             exit(3);
         }
         Ok(s) => s,
     };
-    let mut rec = match eval(sexprs.into_iter()) {
+    let path = if file == "-" {
+        None
+    } else {
+        Some(Path::new(&file))
+    };
+    let mut rec = match eval_file(sexprs.into_iter(), name, path, &opts.overrides) {
         Err(e) => {
-            eprintln!("{:?}", e);
+            report(e);
             exit(4);
         }
         Ok(s) => s,
@@ -150,15 +268,94 @@ fn main() {
 
     condln!(v, Verbosity::Debug, "recipe: {:#?}", rec);
     rec.set_verbosity(v);
+    rec.set_jobs(opts.jobs);
+    rec.set_keep_going(opts.keep_going);
+    rec.set_keep_failed(opts.keep_failed);
+    let state = match State::load(STATE_FILE) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}: ignoring build state: {}", STATE_FILE, e);
+            State::new()
+        }
+    };
+    rec.set_state(state);
+    rec.set_hashing(opts.hash);
 
-    let queue = determine_targets(&rec, targets);
-    if queue == None {
+    let queue = determine_targets(&mut rec, opts.targets);
+    if queue.is_none() {
         exit(5);
     }
-    println!("Targets: {:?}.", queue);
-    for cur in queue.unwrap().into_iter() {
-        if !run_target(&rec, cur) {
-            exit(6);
+    // Rules made from patterns for the requested targets may close a cycle.
+    if let Err(e) = rec.check_cycles() {
+        report(e.into());
+        exit(4);
+    }
+    let targets = Vec::from(queue.clone().unwrap());
+    // Exporting the graph builds nothing.
+    if let Some(format) = opts.graph {
+        match rec.graph(&targets, Box::new(regenerate)) {
+            Ok(graph) => match format {
+                GraphFormat::Dot => println!("{}", graph.to_dot()),
+                GraphFormat::Json => println!("{}", graph.to_json()),
+            },
+            Err(e) => {
+                eprintln!("Unable to export graph: {}", e);
+                exit(6);
+            }
+        }
+        exit(0);
+    }
+    // Missing source files would only make the commands needing them fail in
+    // confusing ways, so nothing is run if there are any.
+    if let Err(e) = rec.check_missing(&targets) {
+        eprintln!("{}", e);
+        exit(5);
+    }
+    let mode = if opts.question {
+        Mode::Question
+    } else if opts.dry_run {
+        Mode::DryRun
+    } else {
+        Mode::Build
+    };
+    if mode != Mode::Question {
+        println!("Targets: {:?}.", queue);
+    }
+    // Logs are only written when something is really built.
+    let log_dir = opts.log_dir.as_deref().map(Path::new);
+    if let (Mode::Build, Some(dir)) = (mode, log_dir) {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("{}: unable to create log directory: {}", dir.display(), e);
+            exit(1);
+        }
+    }
+    // Only real builds have commands running that need to be stopped before
+    // exiting.
+    if mode == Mode::Build {
+        signals::install();
+    }
+    let mut summary = Summary::default();
+    let stale = Arc::new(AtomicBool::new(false));
+    let inerr = !run_targets(&rec, &targets, mode, log_dir, stale.clone(), &mut summary);
+    // Nothing was built in dry runs or questions, so there is nothing new to
+    // remember either.
+    if let (Mode::Build, Some(state)) = (mode, rec.take_state()) {
+        if let Err(e) = state.save(STATE_FILE) {
+            eprintln!("{}: unable to save build state: {}", STATE_FILE, e);
         }
     }
+    // Like shells do, tell the signal in the exit code.
+    if let Some(sig) = signals::received() {
+        exit(128 + sig);
+    }
+    if !summary.is_empty() {
+        summary.print();
+    }
+    if inerr {
+        exit(6);
+    }
+    // Questions tell in the exit code whether anything would be built.
+    if mode == Mode::Question && stale.load(Ordering::SeqCst) {
+        exit(1);
+    }
 }
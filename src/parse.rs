@@ -1,41 +1,17 @@
 use std::collections::VecDeque;
-use std::error;
-use std::fmt;
 
-use crate::sexpr::SExpr;
+use crate::error::{Error, Errors, Kind};
+use crate::sexpr::{Pos, SExpr};
 use crate::tokens::Token;
 use crate::tokens::Tokens;
 
-#[derive(Debug, Clone)]
-pub struct ParseError {
-    msg: String,
-    lineno: u64,
-    col: u64,
+fn parse_error(msg: &str, lineno: u64, col: u64) -> Error {
+    Error::new(Kind::Parse, msg).at(Pos::new(lineno, col))
 }
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}: sexpr error: {}", self.lineno, self.col, self.msg)
-    }
-}
-
-impl error::Error for ParseError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        None
-    }
-}
-
-impl ParseError {
-    pub fn new(msg: &str, lineno: u64, col: u64) -> ParseError {
-        ParseError {
-            msg: msg.to_string(),
-            lineno,
-            col,
-        }
-    }
-}
-
-fn list(toks: &mut Tokens) -> Result<SExpr, ParseError> {
+fn list(toks: &mut Tokens) -> Result<SExpr, Error> {
+    // The opening parenthesis has just been popped.
+    let pos = Pos::new(toks.lineno(), toks.col());
     let mut members: Vec<SExpr> = Vec::new();
     let mut end = false;
     while let Some(cur) = toks.pop() {
@@ -47,38 +23,78 @@ fn list(toks: &mut Tokens) -> Result<SExpr, ParseError> {
                 end = true;
                 break;
             }
-            (Token::Str(s), _, _) => members.push(SExpr::Str(s)),
-            (Token::Id(i), _, _) => members.push(SExpr::Id(i)),
+            (Token::Str(s), lineno, col) => members.push(SExpr::Str(s, Pos::new(lineno, col))),
+            (Token::Id(i), lineno, col) => members.push(SExpr::Id(i, Pos::new(lineno, col))),
         }
     }
     if !end {
-        return Err(ParseError::new(
-            "abrupt end of list",
-            toks.lineno(),
-            toks.col(),
-        ));
+        return Err(parse_error("abrupt end of list", toks.lineno(), toks.col()));
     }
-    Ok(SExpr::List(members))
+    Ok(SExpr::List(members, pos))
 }
 
-pub fn parse(mut toks: Tokens) -> Result<VecDeque<SExpr>, ParseError> {
+// Counts the tokens up to the end of the list opening at the front of `toks`,
+// or gives `None` if the list is never closed.
+fn extent(toks: &Tokens) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while let Some((tok, _, _)) = toks.get(i) {
+        i += 1;
+        match tok {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// Drops tokens up to the next opening parenthesis. With `col1`, only one in the
+// first column will do, as it most likely starts the next top-level form.
+fn resync(toks: &mut Tokens, col1: bool) {
+    while let Some((tok, _, col)) = toks.get(0) {
+        if *tok == Token::LParen && (!col1 || col == 1) {
+            break;
+        }
+        toks.pop();
+    }
+}
+
+// Parses all of `toks`. After an error, parsing goes on with the next
+// top-level form, so that all of the errors are found in one go.
+pub fn parse(mut toks: Tokens) -> Result<VecDeque<SExpr>, Errors> {
     let mut sexprs: VecDeque<SExpr> = VecDeque::new();
-    while let Some(cur) = toks.peek() {
-        match cur {
-            Token::LParen => {
-                toks.pop();
-                sexprs.push_back(list(&mut toks)?)
-            }
-            _ => return Err(ParseError::new("expecting '('", toks.lineno(), toks.col())),
+    let mut errs = Errors::new();
+    while let Some((tok, lineno, col)) = toks.get(0) {
+        if *tok != Token::LParen {
+            errs.push(parse_error("expecting '('", lineno, col));
+            resync(&mut toks, false);
+            continue;
+        }
+        if extent(&toks).is_none() {
+            errs.push(parse_error("unclosed list", lineno, col));
+            toks.pop();
+            resync(&mut toks, true);
+            continue;
+        }
+        toks.pop();
+        match list(&mut toks) {
+            Ok(l) => sexprs.push_back(l),
+            Err(e) => errs.push(e),
         }
     }
-    Ok(sexprs)
+    errs.or_ok(sexprs)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const P: Pos = Pos { lineno: 0, col: 0 };
+
     macro_rules! new_toks {
         ($($tok:expr),*) => {{
             let mut _toks = Tokens::new();
@@ -93,7 +109,7 @@ mod tests {
         let mut sexprs = parse(toks).unwrap();
         assert_eq!(1, sexprs.len());
         assert_eq!(
-            SExpr::List(vec!(SExpr::Id("id".to_string()))),
+            SExpr::List(vec!(SExpr::Id("id".to_string(), P)), P),
             sexprs.pop_front().unwrap()
         );
     }
@@ -111,11 +127,11 @@ mod tests {
         let mut sexprs = parse(toks).unwrap();
         assert_eq!(2, sexprs.len());
         assert_eq!(
-            SExpr::List(vec!(SExpr::Id("one".to_string()))),
+            SExpr::List(vec!(SExpr::Id("one".to_string(), P)), P),
             sexprs.pop_front().unwrap()
         );
         assert_eq!(
-            SExpr::List(vec!(SExpr::Id("two".to_string()))),
+            SExpr::List(vec!(SExpr::Id("two".to_string(), P)), P),
             sexprs.pop_front().unwrap()
         );
     }
@@ -133,11 +149,90 @@ mod tests {
         let mut sexprs = parse(toks).unwrap();
         assert_eq!(1, sexprs.len());
         assert_eq!(
-            SExpr::List(vec!(
-                SExpr::Id("id".to_string()),
-                SExpr::List(vec!(SExpr::Str("string".to_string())))
-            )),
+            SExpr::List(
+                vec!(
+                    SExpr::Id("id".to_string(), P),
+                    SExpr::List(vec!(SExpr::Str("string".to_string(), P)), P)
+                ),
+                P
+            ),
+            sexprs.pop_front().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_positions() {
+        let mut toks = Tokens::new();
+        toks.push(Token::LParen, 2, 1);
+        toks.push(Token::Id("id".to_string()), 2, 2);
+        toks.push(Token::Str("string".to_string()), 3, 5);
+        toks.push(Token::RParen, 3, 13);
+        let mut sexprs = parse(toks).unwrap();
+        assert_eq!(
+            SExpr::List(
+                vec!(
+                    SExpr::Id("id".to_string(), Pos::new(2, 2)),
+                    SExpr::Str("string".to_string(), Pos::new(3, 5))
+                ),
+                Pos::new(2, 1)
+            ),
             sexprs.pop_front().unwrap()
         );
     }
+
+    #[test]
+    fn test_parse_errors_after_comment() {
+        let src = "(target \"a\" (\"b\")\n# c\n(target \"c\" (\"d\")\n(set \"X\" \"x\")\n";
+        let errs: Vec<(String, Option<Pos>)> = match parse(crate::lex::lex(src).unwrap()) {
+            Ok(_) => panic!("errors not detected"),
+            Err(e) => e
+                .into_iter()
+                .map(|e| (e.msg().to_string(), e.pos()))
+                .collect(),
+        };
+        assert_eq!(
+            vec![
+                ("unclosed list".to_string(), Some(Pos::new(1, 1))),
+                ("unclosed list".to_string(), Some(Pos::new(3, 1))),
+            ],
+            errs
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut toks = Tokens::new();
+        // (a (b
+        // c
+        // (d)
+        // e)
+        // (f)
+        toks.push(Token::LParen, 1, 1);
+        toks.push(Token::Id("a".to_string()), 1, 2);
+        toks.push(Token::LParen, 1, 4);
+        toks.push(Token::Id("b".to_string()), 1, 5);
+        toks.push(Token::Id("c".to_string()), 2, 1);
+        toks.push(Token::LParen, 3, 1);
+        toks.push(Token::Id("d".to_string()), 3, 2);
+        toks.push(Token::RParen, 3, 3);
+        toks.push(Token::Id("e".to_string()), 4, 1);
+        toks.push(Token::RParen, 4, 2);
+        toks.push(Token::LParen, 5, 1);
+        toks.push(Token::Id("f".to_string()), 5, 2);
+        toks.push(Token::RParen, 5, 3);
+        let errs: Vec<(String, Option<Pos>)> = match parse(toks) {
+            Ok(_) => panic!("errors not detected"),
+            Err(e) => e
+                .into_iter()
+                .map(|e| (e.msg().to_string(), e.pos()))
+                .collect(),
+        };
+        assert_eq!(
+            vec![
+                ("unclosed list".to_string(), Some(Pos::new(1, 1))),
+                ("expecting '('".to_string(), Some(Pos::new(4, 1))),
+            ],
+            errs
+        );
+    }
 }
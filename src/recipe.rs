@@ -1,25 +1,43 @@
-use lazy_static::lazy_static;
-use regex::Regex;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
 
+use crate::depfile;
+use crate::error::{Error, Errors, Kind};
+use crate::expand::{self, Param};
+use crate::export;
 use crate::graph;
 use crate::graph::GraphIndex;
+use crate::sched;
+use crate::sexpr::Pos;
+use crate::state;
 use crate::Verbosity;
 
-lazy_static! {
-    static ref RE_VAR: Regex =
-        Regex::new(r#"^(?P<first>\$[_a-zA-Z)]+)|[^\$](?P<second>\$[_a-zA-Z)]+)"#).unwrap();
-}
-
-type RunFunction = Box<dyn Fn(&Recipe, &String) -> bool>;
-type RegenFunction = Box<dyn Fn(&Thing, &Thing) -> Result<bool, String>>;
+// Runs the expanded commands of a target, returning whether they succeeded.
+pub type RunFunction = Box<dyn Fn(&Recipe, &Thing, &[String]) -> bool + Sync>;
+pub type RegenFunction = Box<dyn Fn(&Thing, &Thing) -> Result<bool, String>>;
 type MarkMemory = HashSet<GraphIndex>;
+// The target that pulled in each thing reachable from the requested targets.
+type Parents = HashMap<GraphIndex, GraphIndex>;
+// The expanded commands of a target, as recorded and as run.
+type Commands = (Vec<String>, Vec<String>);
 
 #[derive(Debug, PartialEq)]
 pub enum SearchResult {
     Cancelled,
     Ok,
+    // Returned instead of `Cancelled` when keeping going after failures.
+    Incomplete {
+        failed: Vec<Thing>,
+        skipped: Vec<Thing>,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -28,15 +46,163 @@ pub enum Thing {
     Pseudo(String),
 }
 
+// Things are displayed as they are written in recipes.
+impl fmt::Display for Thing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Thing::Actual(s) => write!(f, "{:?}", s),
+            Thing::Pseudo(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// Where a rule was declared.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Origin {
+    pub file: Option<String>,
+    pub lineno: u64,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.lineno),
+            None => write!(f, "line {}", self.lineno),
+        }
+    }
+}
+
+// Modification time of an actual thing, if it exists.
+fn modified(thing: &Thing) -> Option<SystemTime> {
+    match thing {
+        Thing::Actual(name) => fs::metadata(name).and_then(|md| md.modified()).ok(),
+        Thing::Pseudo(_) => None,
+    }
+}
+
+// The value of a parameter. Lists are joined with spaces when expanded in
+// strings.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Value {
+    Str(String),
+    List(Vec<String>),
+}
+
+impl Value {
+    // Returns the value as list items, a string being a single item.
+    pub fn items(&self) -> Vec<String> {
+        match self {
+            Value::Str(s) => vec![s.to_string()],
+            Value::List(l) => l.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(l) => write!(f, "{}", l.join(" ")),
+        }
+    }
+}
+
+// Matches `name` against `pattern`, where a single "%" stands for a non-empty
+// stem, and returns the stem. Without a "%" the whole name must match and the
+// stem is empty.
+pub fn match_pattern<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    let i = match pattern.find('%') {
+        Some(i) => i,
+        None if pattern == name => return Some(""),
+        None => return None,
+    };
+    let (prefix, suffix) = (&pattern[..i], &pattern[i + 1..]);
+    if name.len() > prefix.len() + suffix.len()
+        && name.starts_with(prefix)
+        && name.ends_with(suffix)
+    {
+        Some(&name[prefix.len()..name.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+// Optional settings of a single target.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TargetOptions {
+    // Makefile-style file listing further dependencies, written by the
+    // commands of the target.
+    pub depfile: Option<String>,
+    // The target is left alone when its commands fail.
+    pub precious: bool,
+    // Parameters set for the target and everything it pulls in, in the order
+    // they were set.
+    pub vars: Vec<(String, Value)>,
+}
+
+// A rule for every actual target matching `target`, where "%" stands for a
+// non-empty stem. The stem replaces the first "%" in each dependency.
+#[derive(Debug)]
+struct Pattern {
+    target: String,
+    deps: Vec<Thing>,
+    cmds: Vec<String>,
+    options: TargetOptions,
+    origin: Origin,
+}
+
+impl Pattern {
+    fn stem<'a>(&self, name: &'a str) -> Option<&'a str> {
+        match_pattern(&self.target, name)
+    }
+
+    fn deps(&self, stem: &str) -> Vec<Thing> {
+        self.deps
+            .iter()
+            .map(|dep| match dep {
+                Thing::Actual(s) => Thing::Actual(s.replacen('%', stem, 1)),
+                Thing::Pseudo(_) => dep.clone(),
+            })
+            .collect()
+    }
+
+    fn options(&self, stem: &str) -> TargetOptions {
+        TargetOptions {
+            depfile: self
+                .options
+                .depfile
+                .as_ref()
+                .map(|d| d.replacen('%', stem, 1)),
+            precious: self.options.precious,
+            vars: self.options.vars.clone(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Recipe {
     depgraph: graph::DAG<Thing>,
     inverse: HashMap<graph::GraphIndex, Thing>,
     rules: HashMap<Thing, graph::GraphIndex>,
     cmds: HashMap<Thing, Vec<String>>,
-    vars: HashMap<String, String>,
+    origins: HashMap<Thing, Origin>,
+    options: HashMap<Thing, TargetOptions>,
+    patterns: Vec<Pattern>,
+    // The stems of the targets whose rules were made from patterns.
+    stems: HashMap<Thing, String>,
+    vars: HashMap<String, Value>,
+    // Parameters given on the command line, which no `set` can change.
+    fixed: HashSet<String>,
+    // Parameters with values from outside of the recipe, which are inserted
+    // without expanding them.
+    literal: HashSet<String>,
     first: Option<Thing>,
     v: Verbosity,
+    jobs: usize,
+    keep_going: bool,
+    keep_failed: bool,
+    hashing: bool,
+    state: Option<Mutex<state::State>>,
 }
 
 impl Default for Recipe {
@@ -52,17 +218,28 @@ impl Recipe {
             inverse: HashMap::new(),
             rules: HashMap::new(),
             vars: HashMap::new(),
+            fixed: HashSet::new(),
+            literal: HashSet::new(),
             cmds: HashMap::new(),
+            origins: HashMap::new(),
+            options: HashMap::new(),
+            patterns: Vec::new(),
+            stems: HashMap::new(),
             first: None,
             v: Verbosity::Minimal,
+            jobs: 1,
+            keep_going: false,
+            keep_failed: false,
+            hashing: false,
+            state: None,
         }
     }
 
-    pub fn add_rule<T>(&mut self, thing: Thing, deps: T, cmds: Vec<String>)
+    pub fn add_rule<T>(&mut self, thing: Thing, deps: T, cmds: Vec<String>, origin: Origin)
     where
         T: Iterator<Item = Thing>,
     {
-        if self.first == None {
+        if self.first.is_none() {
             self.first = Some(thing.clone());
         }
         // When we are adding a rule for a target, we might have seen it before
@@ -76,7 +253,8 @@ impl Recipe {
             self.inverse.insert(i, thing.clone());
             i
         };
-        self.cmds.insert(thing, cmds);
+        self.cmds.insert(thing.clone(), cmds);
+        self.origins.insert(thing, origin);
         // When inserting a new rule into the dependency graph, we have to make
         // sure all its dependencies are
         //
@@ -96,178 +274,788 @@ impl Recipe {
         }
     }
 
+    // Looks parameter `name` up in `overrides`, then among the global ones.
+    fn param(&self, overrides: Option<&HashMap<String, String>>, name: &str) -> Option<Param> {
+        if let Some(value) = overrides.and_then(|or| or.get(name)) {
+            return Some(Param::Value(value.to_string()));
+        }
+        let value = self.get_var(name)?.to_string();
+        if self.literal.contains(name) {
+            Some(Param::Literal(value))
+        } else {
+            Some(Param::Value(value))
+        }
+    }
+
     fn expand_vars(
         &self,
         overrides: Option<HashMap<String, String>>,
         msg: &str,
     ) -> Result<String, String> {
         condln!(self.v, Verbosity::Debug, "[v] expanding vars: {:?}", msg);
-        let mut ret = msg.to_string();
-
-        // We have to wrap our parameter/variable expansion into a restart loop,
-        // because a single replacement invalidates the rest of the matches in
-        // the iterator.
-        'restart: loop {
-            'iter: for m in RE_VAR.captures_iter(&ret) {
-                condln!(self.v, Verbosity::Debug, "[v] match={:?}", m);
-                let (name, start, end) = if let Some(var) = m.name("first") {
-                    (&var.as_str()[1..], var.start(), var.end())
-                } else if let Some(var) = m.name("second") {
-                    (&var.as_str()[1..], var.start(), var.end())
-                } else {
-                    continue 'iter;
-                };
-                condln!(
-                    self.v,
-                    Verbosity::Debug,
-                    "[v] match: '{}' ({}, {})",
-                    name,
-                    start,
-                    end
-                );
-                let mut replaced = false;
-                let mut rep = "";
-                if let Some(vrep) = self.get_var(name) {
-                    rep = vrep;
-                    replaced = true;
-                }
-                if let Some(ref or) = overrides {
-                    if let Some(orep) = or.get(name) {
-                        rep = orep;
-                        replaced = true;
-                    }
-                }
-                if !replaced {
-                    return Err(format!("Unrecognized parameter in expansion: {}", name));
-                }
-
-                ret.replace_range(start..end, rep);
-                condln!(self.v, Verbosity::Debug, "[v] after replacement: {}", ret);
-                continue 'restart;
-            }
-            break 'restart;
-        }
-        // The regex above meant that all double-dollars are left alone, so we
-        // must handle them here. This has the semi-intentional side-effect of
-        // supporting more general build scripts and building of shell commands.
-        // See `examples/ex01.pma` and the "BUILD" parameter for an example.
-        Ok(ret.replace("$$", "$"))
+        let lookup = |name: &str| self.param(overrides.as_ref(), name);
+        let ret = expand::expand(msg, &lookup)?;
+        condln!(self.v, Verbosity::Debug, "[v] after expansion: {}", ret);
+        Ok(ret)
     }
 
+    // Lists every node reachable from `target` so that dependencies always
+    // come before their dependents.
     pub fn toposort(
         &self,
         memmark: &mut MarkMemory,
         target: GraphIndex,
-        runner: &RunFunction,
-        regener: &RegenFunction,
-    ) -> SearchResult {
+        order: &mut Vec<GraphIndex>,
+    ) {
         // Has this node been visited before?
         if memmark.contains(&target) {
-            return SearchResult::Ok;
+            return;
         }
         memmark.insert(target);
+        for dep in self.depgraph.successors(target).unwrap() {
+            self.toposort(memmark, dep, order);
+        }
+        order.push(target);
+    }
 
+    // Lists the things reachable from `roots` along with the target that
+    // pulled each of them in. Going breadth-first in the order dependencies
+    // were declared, the target nearest to the roots wins.
+    fn parents(&self, roots: &[GraphIndex]) -> Parents {
+        let mut parents = Parents::new();
+        let mut seen: HashSet<GraphIndex> = roots.iter().copied().collect();
+        let mut queue: VecDeque<GraphIndex> = roots.iter().copied().collect();
+        while let Some(target) = queue.pop_front() {
+            let mut deps: Vec<GraphIndex> = self.depgraph.successors(target).unwrap().collect();
+            deps.reverse();
+            for dep in deps {
+                if seen.insert(dep) {
+                    parents.insert(dep, target);
+                    queue.push_back(dep);
+                }
+            }
+        }
+        parents
+    }
+
+    // Collects the parameters set for `target` and for the targets that pulled
+    // it in. The ones set nearest to `target`, and last within a target, win.
+    fn scope(&self, target: GraphIndex, parents: &Parents) -> HashMap<String, String> {
+        let mut scope = HashMap::new();
+        let mut cur = Some(target);
+        while let Some(i) = cur {
+            if let Some(options) = self.options.get(self.inverse.get(&i).unwrap()) {
+                for (name, value) in options.vars.iter().rev() {
+                    if self.fixed.contains(name) {
+                        continue;
+                    }
+                    scope
+                        .entry(name.to_string())
+                        .or_insert_with(|| value.to_string());
+                }
+            }
+            cur = parents.get(&i).copied();
+        }
+        scope
+    }
+
+    // Fills in the automatic parameters of `target`. A dependency is newer
+    // than the target if it was rebuilt, or if it was modified after the
+    // target. Without `rebuilt`, all dependencies count as newer.
+    fn automatic(
+        &self,
+        target: GraphIndex,
+        rebuilt: Option<&HashSet<GraphIndex>>,
+    ) -> HashMap<String, String> {
+        let thingtarget = self.inverse.get(&target).unwrap();
+        let name = |thing: &Thing| match thing {
+            Thing::Actual(s) | Thing::Pseudo(s) => s.to_string(),
+        };
+        // Successors come last to first, and dependencies are listed in the
+        // order they were declared.
+        let mut deps: Vec<GraphIndex> = self.depgraph.successors(target).unwrap().collect();
+        deps.reverse();
+        let deps: Vec<&Thing> = deps.iter().map(|i| self.inverse.get(i).unwrap()).collect();
+        let modtarget = modified(thingtarget);
+        let newer = |dep: &Thing| match rebuilt {
+            None => true,
+            Some(rebuilt) => {
+                rebuilt.contains(self.rules.get(dep).unwrap())
+                    || match (modtarget, modified(dep)) {
+                        (Some(t), Some(d)) => d > t,
+                        _ => true,
+                    }
+            }
+        };
+        let list = |deps: Vec<&&Thing>| {
+            deps.into_iter()
+                .map(|d| name(d))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        let stem = self.stems.get(thingtarget).cloned().unwrap_or_default();
+        HashMap::from([
+            ("TARGET".to_string(), name(thingtarget)),
+            ("TARGET_DIR".to_string(), expand::dir(&name(thingtarget))),
+            ("TARGET_BASE".to_string(), expand::base(&name(thingtarget))),
+            ("STEM".to_string(), stem),
+            ("DEPS".to_string(), list(deps.iter().collect())),
+            (
+                "FIRST_DEP".to_string(),
+                deps.first().map(|d| name(d)).unwrap_or_default(),
+            ),
+            (
+                "NEWER_DEPS".to_string(),
+                list(deps.iter().filter(|d| newer(d)).collect()),
+            ),
+            (
+                "FILE_DEPS".to_string(),
+                list(
+                    deps.iter()
+                        .filter(|d| matches!(d, Thing::Actual(_)))
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+
+    // Expands the commands of `target`, for running them if `rebuilt` is
+    // given. Otherwise they are expanded for the build state, where they have
+    // to stay the same from one build to the next.
+    fn expand_cmds(
+        &self,
+        target: GraphIndex,
+        parents: &Parents,
+        rebuilt: Option<&HashSet<GraphIndex>>,
+    ) -> Result<Vec<String>, Error> {
         let thingtarget = self.inverse.get(&target).unwrap();
-        let succ = self.depgraph.successors(target).unwrap();
+        let cmds = match self.cmds.get(thingtarget) {
+            Some(cmds) => cmds,
+            None => return Ok(Vec::new()),
+        };
+        // Target parameters override global ones, and automatic parameters
+        // override both.
+        let mut overrides = self.scope(target, parents);
+        overrides.extend(self.automatic(target, rebuilt));
+        let mut ecmds = Vec::new();
+        for cmd in cmds.iter() {
+            match self.expand_vars(Some(overrides.clone()), cmd) {
+                Ok(ecmd) => ecmds.push(ecmd),
+                Err(e) => {
+                    let e = Error::new(Kind::Expansion, &e);
+                    return Err(match self.origins.get(thingtarget) {
+                        Some(origin) => e
+                            .at(Pos::new(origin.lineno, 0))
+                            .in_file(origin.file.as_deref()),
+                        None => e,
+                    });
+                }
+            }
+        }
+        Ok(ecmds)
+    }
+
+    // Decides whether `target`, to be built with `ecmds`, has to be
+    // regenerated. A target is also regenerated if any of its dependencies in
+    // `rebuilt` was, or if it was last built with other commands. When
+    // hashing, actual dependencies are compared against the contents recorded
+    // at the previous build instead of asking `regener`.
+    fn outdated(
+        &self,
+        target: GraphIndex,
+        ecmds: &[String],
+        rebuilt: &HashSet<GraphIndex>,
+        regener: &RegenFunction,
+    ) -> Result<bool, String> {
+        let thingtarget = self.inverse.get(&target).unwrap();
+        let entry = match (thingtarget, &self.state) {
+            (Thing::Actual(name), Some(state)) => state.lock().unwrap().get(name).cloned(),
+            _ => None,
+        };
         let mut regen = false;
         let mut nsucc = 0u64;
-        for dep in succ.into_iter() {
+        for dep in self.depgraph.successors(target).unwrap() {
             nsucc += 1;
             let thingdep = self.inverse.get(&dep).unwrap();
             condln!(
                 self.v,
                 Verbosity::Verbose,
-                "[] target={:?}, dep={:?}",
+                "[] target={:?}, dep={:?}",
                 thingtarget,
                 thingdep
             );
-            match self.toposort(memmark, dep, runner, regener) {
-                r @ SearchResult::Cancelled => return r,
-                SearchResult::Ok => (),
+            if rebuilt.contains(&dep) {
+                // Whatever the dependency looks like now, the runner may not
+                // have touched it at all, as in dry runs.
+                condln!(
+                    self.v,
+                    Verbosity::Verbose,
+                    "[?] => dependency rebuilt, regenerating {:?}",
+                    thingtarget
+                );
+                regen = true;
+                continue;
             }
-            match regener(thingtarget, thingdep) {
-                Err(e) => {
-                    condln!(self.v, Verbosity::Verbose, "[!] {:?}", e);
-                    return SearchResult::Cancelled;
+            let recorded = match (&entry, thingdep) {
+                (Some(entry), Thing::Actual(name)) if self.hashing => {
+                    entry.dep_hash(name).map(|hash| (name, hash))
                 }
-                Ok(false) => {
-                    condln!(self.v, Verbosity::Verbose, "[?] => target not out of date.");
+                _ => None,
+            };
+            let changed = match recorded {
+                Some((name, hash)) => state::hash_file(name).ok() != Some(hash),
+                None => regener(thingtarget, thingdep)?,
+            };
+            if changed {
+                condln!(
+                    self.v,
+                    Verbosity::Verbose,
+                    "[?] => regenerating {:?}",
+                    thingtarget
+                );
+                regen = true;
+            } else {
+                condln!(self.v, Verbosity::Verbose, "[?] => target not out of date.");
+            }
+        }
+        // Dependencies from the depfile are only known from the last build.
+        for extra in entry.iter().flat_map(|e| e.extra.iter()) {
+            let recorded = match &entry {
+                Some(entry) if self.hashing => entry.dep_hash(extra),
+                _ => None,
+            };
+            let changed = if !Path::new(extra).exists() {
+                true
+            } else {
+                match recorded {
+                    Some(hash) => state::hash_file(extra).ok() != Some(hash),
+                    None => regener(thingtarget, &Thing::Actual(extra.to_string()))?,
+                }
+            };
+            if changed {
+                condln!(
+                    self.v,
+                    Verbosity::Verbose,
+                    "[?] => depfile dependency {:?} changed",
+                    extra
+                );
+                regen = true;
+            }
+        }
+        if let (Some(entry), Thing::Actual(name)) = (&entry, thingtarget) {
+            if entry.cmds != ecmds {
+                condln!(self.v, Verbosity::Verbose, "[?] => commands changed");
+                regen = true;
+            }
+            if self.hashing && !Path::new(name).exists() {
+                condln!(self.v, Verbosity::Verbose, "[?] => target missing");
+                regen = true;
+            }
+        }
+        Ok(regen || nsucc == 0)
+    }
+
+    // Returns the expanded commands of `target` if it has to be regenerated,
+    // both as they are to be recorded and as they are to be run.
+    fn prepare(
+        &self,
+        target: GraphIndex,
+        parents: &Parents,
+        rebuilt: &HashSet<GraphIndex>,
+        regener: &RegenFunction,
+    ) -> Result<Option<Commands>, ()> {
+        let expand = |rebuilt| match self.expand_cmds(target, parents, rebuilt) {
+            Ok(e) => Ok(e),
+            Err(e) => {
+                eprintln!("{}", e);
+                Err(())
+            }
+        };
+        let ecmds = expand(None)?;
+        match self.outdated(target, &ecmds, rebuilt, regener) {
+            Err(e) => {
+                condln!(self.v, Verbosity::Verbose, "[!] {:?}", e);
+                Err(())
+            }
+            Ok(true) if !ecmds.is_empty() => {
+                let rcmds = expand(Some(rebuilt))?;
+                Ok(Some((ecmds, rcmds)))
+            }
+            Ok(_) => {
+                self.record(target, &ecmds, true);
+                Ok(None)
+            }
+        }
+    }
+
+    // Updates the state of `target` after it was built with `ecmds` or found
+    // up to date. The state of a failed target is dropped. Dependencies are
+    // hashed only when hashing. Dependencies listed in the depfile of the
+    // target, if it has one, are remembered for the next run.
+    fn record(&self, target: GraphIndex, ecmds: &[String], ok: bool) {
+        let thingtarget = self.inverse.get(&target).unwrap();
+        let (name, state) = match (thingtarget, &self.state) {
+            (Thing::Actual(name), Some(state)) if self.cmds.contains_key(thingtarget) => {
+                (name, state)
+            }
+            _ => return,
+        };
+        if !ok {
+            state.lock().unwrap().forget(name);
+            return;
+        }
+        let direct: Vec<String> = self
+            .depgraph
+            .successors(target)
+            .unwrap()
+            .filter_map(|dep| match self.inverse.get(&dep).unwrap() {
+                Thing::Actual(dname) => Some(dname.to_string()),
+                Thing::Pseudo(_) => None,
+            })
+            .collect();
+        let extra = match self
+            .options
+            .get(thingtarget)
+            .and_then(|o| o.depfile.as_ref())
+        {
+            Some(depfile) => match depfile::read(depfile) {
+                Ok(deps) => deps
+                    .into_iter()
+                    .filter(|d| d != name && !direct.contains(d))
+                    .collect(),
+                Err(e) => {
+                    condln!(self.v, Verbosity::Verbose, "[!] {}: {}", depfile, e);
+                    Vec::new()
                 }
-                Ok(true) => {
-                    condln!(
-                        self.v,
-                        Verbosity::Verbose,
-                        "[?] => regenerating {:?}",
-                        thingtarget
-                    );
-                    regen = true;
+            },
+            None => Vec::new(),
+        };
+        let mut entry = state::Entry {
+            cmds: ecmds.to_vec(),
+            deps: Vec::new(),
+            extra,
+        };
+        if self.hashing {
+            for dname in direct.iter().chain(entry.extra.iter()) {
+                if let Ok(hash) = state::hash_file(dname) {
+                    entry.deps.push((dname.to_string(), hash));
                 }
             }
         }
-        if regen || nsucc == 0 {
-            if let Some(cmds) = self.cmds.get(thingtarget) {
-                for cmd in cmds.iter() {
-                    let mut overrides: HashMap<String, String> = HashMap::new();
-                    match thingtarget {
-                        Thing::Actual(s) | Thing::Pseudo(s) => {
-                            overrides.insert("TARGET".to_string(), s.to_string());
-                            overrides.insert(
-                                "DEPS".to_string(),
-                                self.depgraph
-                                    .successors(target)
-                                    .unwrap()
-                                    .into_iter()
-                                    .map(|v| self.inverse.get(&v).unwrap())
-                                    .map(|v| match v {
-                                        Thing::Actual(s) | Thing::Pseudo(s) => s.to_string(),
-                                    })
-                                    .collect::<Vec<String>>()
-                                    .join(" "),
-                            );
-                        }
-                    }
-                    let ecmd = match self.expand_vars(Some(overrides), cmd) {
-                        Ok(e) => e,
-                        Err(e) => {
-                            eprintln!("Command expansion failed: {:?}", e);
-                            return SearchResult::Cancelled;
-                        }
-                    };
-                    if !runner(&self, &ecmd) {
-                        return SearchResult::Cancelled;
+        state.lock().unwrap().record(name, entry);
+    }
+
+    // Gets rid of what the failed commands of `thing` left behind, unless it is
+    // precious. A target file is only touched if the commands changed it, as
+    // an older one is harmless: it is still out of date.
+    fn discard(&self, thing: &Thing, before: Option<SystemTime>) {
+        let name = match thing {
+            Thing::Actual(name) => name,
+            Thing::Pseudo(_) => return,
+        };
+        let precious = self.options.get(thing).is_some_and(|o| o.precious);
+        let after = modified(thing);
+        if precious || after.is_none() || after == before {
+            return;
+        }
+        let res = if self.keep_failed {
+            let aside = format!("{}.failed", name);
+            eprintln!("Moving failed target {} to {:?}", thing, aside);
+            fs::rename(name, aside)
+        } else {
+            eprintln!("Deleting failed target {}", thing);
+            fs::remove_file(name)
+        };
+        if let Err(e) = res {
+            eprintln!("{}: {}", name, e);
+        }
+    }
+
+    // Runs the out-of-date parts of `order` with at most `self.jobs` targets
+    // being built at the same time. After the first failure no new targets
+    // are started, but the ones already running are waited for. When keeping
+    // going, only the targets depending on a failed one are left out.
+    fn schedule(
+        &self,
+        order: Vec<GraphIndex>,
+        parents: &Parents,
+        runner: &RunFunction,
+        regener: &RegenFunction,
+    ) -> SearchResult {
+        let mut sched = sched::Schedule::new(&self.depgraph, order);
+        let mut rebuilt: HashSet<GraphIndex> = HashSet::new();
+        let mut failed: Vec<GraphIndex> = Vec::new();
+        let mut running = 0usize;
+        let (tx, rx) = mpsc::channel::<(GraphIndex, Vec<String>, bool)>();
+        thread::scope(|s| loop {
+            while (failed.is_empty() || self.keep_going) && running < self.jobs {
+                let target = match sched.pop() {
+                    Some(t) => t,
+                    None => break,
+                };
+                match self.prepare(target, parents, &rebuilt, regener) {
+                    Err(_) => failed.push(target),
+                    Ok(None) => sched.done(target),
+                    Ok(Some((ecmds, rcmds))) => {
+                        let tx = tx.clone();
+                        running += 1;
+                        s.spawn(move || {
+                            let thing = self.inverse.get(&target).unwrap();
+                            let before = modified(thing);
+                            let ok = runner(self, thing, &rcmds);
+                            if !ok {
+                                self.discard(thing, before);
+                            }
+                            tx.send((target, ecmds, ok)).unwrap();
+                        });
                     }
                 }
             }
+            if running == 0 {
+                break;
+            }
+            let (target, ecmds, ok) = rx.recv().unwrap();
+            running -= 1;
+            self.record(target, &ecmds, ok);
+            if ok {
+                rebuilt.insert(target);
+                sched.done(target);
+            } else {
+                failed.push(target);
+            }
+        });
+        if failed.is_empty() {
+            SearchResult::Ok
+        } else if self.keep_going {
+            let things = |v: Vec<GraphIndex>| {
+                v.iter()
+                    .map(|i| self.inverse.get(i).unwrap().clone())
+                    .collect::<Vec<Thing>>()
+            };
+            SearchResult::Incomplete {
+                failed: things(failed),
+                skipped: things(sched.blocked()),
+            }
+        } else {
+            SearchResult::Cancelled
         }
-        SearchResult::Ok
     }
 
+    // Builds what is out of date among `targets` and what they depend on, in
+    // a single schedule so that dependencies they share are built once.
     pub fn evaluate(
         &self,
-        thing: &Thing,
+        targets: &[Thing],
         runner: RunFunction,
         regener: RegenFunction,
     ) -> SearchResult {
-        let ti = self.rules.get(thing).unwrap();
+        let roots: Vec<GraphIndex> = targets
+            .iter()
+            .map(|thing| *self.rules.get(thing).unwrap())
+            .collect();
         let mut mm = MarkMemory::new();
-        self.toposort(&mut mm, *ti, &runner, &regener)
+        let mut order = Vec::new();
+        for &root in roots.iter() {
+            self.toposort(&mut mm, root, &mut order);
+        }
+        let parents = self.parents(&roots);
+        self.schedule(order, &parents, &runner, &regener)
     }
 
-    pub fn set_var(&mut self, name: &str, val: &str) -> Result<(), String> {
-        let eval = match self.expand_vars(None, &val) {
-            Ok(e) => e,
-            Err(e) => {
-                return Err(format!("Parameter expansion failed: {:?}", e));
-            }
+    // Describes the graph reachable from `targets` and which of its things
+    // would be regenerated by a build, without building or recording
+    // anything.
+    pub fn graph(&self, targets: &[Thing], regener: RegenFunction) -> Result<export::Graph, Error> {
+        let roots: Vec<GraphIndex> = targets
+            .iter()
+            .map(|thing| *self.rules.get(thing).unwrap())
+            .collect();
+        let mut mm = MarkMemory::new();
+        let mut order = Vec::new();
+        for &root in roots.iter() {
+            self.toposort(&mut mm, root, &mut order);
+        }
+        let parents = self.parents(&roots);
+        let ids: HashMap<GraphIndex, usize> =
+            order.iter().enumerate().map(|(n, &i)| (i, n)).collect();
+        let mut graph = export::Graph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
         };
-        self.vars.insert(name.to_string(), eval);
-        Ok(())
+        let mut rebuilt: HashSet<GraphIndex> = HashSet::new();
+        for (n, &target) in order.iter().enumerate() {
+            let ecmds = self.expand_cmds(target, &parents, None)?;
+            let stale = !ecmds.is_empty()
+                && self
+                    .outdated(target, &ecmds, &rebuilt, &regener)
+                    .map_err(|e| Error::new(Kind::Build, &e))?;
+            if stale {
+                rebuilt.insert(target);
+            }
+            let thing = self.inverse.get(&target).unwrap();
+            graph.nodes.push(export::Node {
+                thing: thing.clone(),
+                rule: self.cmds.contains_key(thing),
+                stale,
+            });
+            let mut deps: Vec<GraphIndex> = self.depgraph.successors(target).unwrap().collect();
+            deps.reverse();
+            for dep in deps {
+                let edge = (n, *ids.get(&dep).unwrap());
+                if !graph.edges.contains(&edge) {
+                    graph.edges.push(edge);
+                }
+            }
+        }
+        Ok(graph)
     }
 
-    pub fn get_var(&self, name: &str) -> Option<&String> {
+    // Sets a parameter to a value whose strings are already expanded, unless
+    // it was overridden.
+    pub fn set_value(&mut self, name: &str, val: Value) {
+        if !self.fixed.contains(name) {
+            self.vars.insert(name.to_string(), val);
+            self.literal.remove(name);
+        }
+    }
+
+    // Sets a parameter to a value that is inserted as it is, unless it was
+    // overridden.
+    pub fn set_literal(&mut self, name: &str, val: Value) {
+        if !self.fixed.contains(name) {
+            self.vars.insert(name.to_string(), val);
+            self.literal.insert(name.to_string());
+        }
+    }
+
+    // Overrides parameter `name` with `val`, taken as it is. Any `set` of the
+    // parameter, global or for a target, is ignored from then on.
+    pub fn override_var(&mut self, name: &str, val: &str) {
+        self.vars
+            .insert(name.to_string(), Value::Str(val.to_string()));
+        self.fixed.insert(name.to_string());
+        self.literal.insert(name.to_string());
+    }
+
+    pub fn is_literal(&self, name: &str) -> bool {
+        self.literal.contains(name)
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&Value> {
         self.vars.get(name)
     }
 
+    // Expands the parameters in `s` like in a `set` value, which is expanded
+    // again when inserted.
+    pub fn expand(&self, s: &str) -> Result<String, Error> {
+        expand::expand_value(s, &|name: &str| self.param(None, name))
+            .map_err(|e| Error::new(Kind::Expansion, &e))
+    }
+
+    pub fn add_pattern(
+        &mut self,
+        target: String,
+        deps: Vec<Thing>,
+        cmds: Vec<String>,
+        options: TargetOptions,
+        origin: Origin,
+    ) {
+        self.patterns.push(Pattern {
+            target,
+            deps,
+            cmds,
+            options,
+            origin,
+        });
+    }
+
+    pub fn set_options(&mut self, thing: Thing, options: TargetOptions) {
+        self.options.insert(thing, options);
+    }
+
+    pub fn get_options(&self, thing: &Thing) -> Option<&TargetOptions> {
+        self.options.get(thing)
+    }
+
+    // Finds the first pattern matching `name` whose dependencies all exist or
+    // can be made. `seen` holds the names being looked at further up the
+    // chain, so that patterns like "%" from "%.x" do not go on forever.
+    fn find_pattern(&self, name: &str, seen: &mut HashSet<String>) -> Option<(usize, String)> {
+        if !seen.insert(name.to_string()) {
+            return None;
+        }
+        let mut found = None;
+        for (n, pattern) in self.patterns.iter().enumerate() {
+            let stem = match pattern.stem(name) {
+                Some(stem) => stem,
+                None => continue,
+            };
+            if pattern
+                .deps(stem)
+                .iter()
+                .all(|dep| self.can_make(dep, seen))
+            {
+                found = Some((n, stem.to_string()));
+                break;
+            }
+        }
+        seen.remove(name);
+        found
+    }
+
+    fn can_make(&self, thing: &Thing, seen: &mut HashSet<String>) -> bool {
+        match thing {
+            Thing::Pseudo(_) => true,
+            Thing::Actual(name) => {
+                self.cmds.contains_key(thing)
+                    || Path::new(name).exists()
+                    || self.find_pattern(name, seen).is_some()
+            }
+        }
+    }
+
+    // Adds a rule from a pattern for `thing` unless it has an explicit one,
+    // and does the same for the dependencies that come with it. Returns
+    // whether `thing` has a rule afterwards.
+    pub fn add_pattern_rules(&mut self, thing: &Thing) -> bool {
+        // Rules made from patterns never become the default target.
+        let first = self.first.clone();
+        let mut queue = vec![thing.clone()];
+        while let Some(cur) = queue.pop() {
+            let name = match &cur {
+                Thing::Actual(name) if !self.cmds.contains_key(&cur) => name,
+                _ => continue,
+            };
+            let (n, stem) = match self.find_pattern(name, &mut HashSet::new()) {
+                Some(found) => found,
+                None => continue,
+            };
+            let pattern = &self.patterns[n];
+            let deps = pattern.deps(&stem);
+            let cmds = pattern.cmds.clone();
+            let options = pattern.options(&stem);
+            let origin = pattern.origin.clone();
+            condln!(
+                self.v,
+                Verbosity::Debug,
+                "[p] {:?} from pattern {:?}",
+                cur,
+                pattern.target
+            );
+            queue.extend(deps.iter().cloned());
+            self.stems.insert(cur.clone(), stem);
+            self.set_options(cur.clone(), options);
+            self.add_rule(cur, deps.into_iter(), cmds, origin);
+        }
+        self.first = first;
+        self.cmds.contains_key(thing)
+    }
+
+    // Adds rules from patterns for everything mentioned without an explicit
+    // rule.
+    pub fn apply_patterns(&mut self) {
+        if self.patterns.is_empty() {
+            return;
+        }
+        let things: Vec<Thing> = (0..self.inverse.len())
+            .map(|i| self.inverse.get(&i).unwrap().clone())
+            .collect();
+        for thing in things.iter() {
+            self.add_pattern_rules(thing);
+        }
+    }
+
+    // Describes a dependency cycle, if there is one, along with where the
+    // rules in it were declared.
+    pub fn check_cycles(&self) -> Result<(), Error> {
+        let cycle = match self.find_cycle() {
+            Some(cycle) => cycle,
+            None => return Ok(()),
+        };
+        let chain = cycle
+            .iter()
+            .enumerate()
+            .map(|(n, thing)| match self.get_origin(thing) {
+                Some(origin) if n + 1 < cycle.len() => format!("{} ({})", thing, origin),
+                _ => thing.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(" -> ");
+        Err(Error::new(
+            Kind::Syntax,
+            &format!("dependency cycle: {}", chain),
+        ))
+    }
+
+    // Reports every actual file reachable from `targets` that has no rule and
+    // does not exist, along with the first rule needing it.
+    pub fn check_missing(&self, targets: &[Thing]) -> Result<(), Errors> {
+        let missing = |thing: &Thing| {
+            matches!(thing, Thing::Actual(_))
+                && !self.cmds.contains_key(thing)
+                && modified(thing).is_none()
+        };
+        let mut errs = Errors::new();
+        let mut seen: HashSet<GraphIndex> = HashSet::new();
+        let mut queue: VecDeque<GraphIndex> = VecDeque::new();
+        for thing in targets.iter() {
+            let i = *self.rules.get(thing).unwrap();
+            if !seen.insert(i) {
+                continue;
+            }
+            if missing(thing) {
+                errs.push(Error::new(
+                    Kind::Build,
+                    &format!("no rule to make {}", thing),
+                ));
+            }
+            queue.push_back(i);
+        }
+        // Going breadth-first in the order dependencies were declared reports
+        // them in about the order they appear in the recipe.
+        while let Some(target) = queue.pop_front() {
+            let thing = self.inverse.get(&target).unwrap();
+            let mut deps: Vec<GraphIndex> = self.depgraph.successors(target).unwrap().collect();
+            deps.reverse();
+            for dep in deps {
+                if !seen.insert(dep) {
+                    continue;
+                }
+                queue.push_back(dep);
+                let depthing = self.inverse.get(&dep).unwrap();
+                if !missing(depthing) {
+                    continue;
+                }
+                let msg = format!("no rule to make {}, needed by {}", depthing, thing);
+                let mut e = Error::new(Kind::Build, &msg);
+                if let Some(origin) = self.get_origin(thing) {
+                    e = e
+                        .at(Pos::new(origin.lineno, 0))
+                        .in_file(origin.file.as_deref());
+                }
+                errs.push(e);
+            }
+        }
+        errs.or_ok(())
+    }
+
+    // Returns the things forming a dependency cycle, if there is one. The
+    // first thing is repeated at the end.
+    pub fn find_cycle(&self) -> Option<Vec<Thing>> {
+        let cycle = self.depgraph.find_cycle()?;
+        Some(
+            cycle
+                .into_iter()
+                .map(|i| self.inverse.get(&i).unwrap().clone())
+                .collect(),
+        )
+    }
+
+    // Returns where the rule for `thing` was declared.
+    pub fn get_origin(&self, thing: &Thing) -> Option<&Origin> {
+        self.origins.get(thing)
+    }
+
     pub fn get_default(&self) -> Option<&Thing> {
         self.first.as_ref()
     }
@@ -287,4 +1075,50 @@ impl Recipe {
     pub fn get_verbosity(&self) -> Verbosity {
         self.v
     }
+
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+
+    pub fn get_jobs(&self) -> usize {
+        self.jobs
+    }
+
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+
+    pub fn get_keep_going(&self) -> bool {
+        self.keep_going
+    }
+
+    // With `keep_failed`, targets whose commands fail are moved aside instead
+    // of being deleted.
+    pub fn set_keep_failed(&mut self, keep_failed: bool) {
+        self.keep_failed = keep_failed;
+    }
+
+    pub fn get_keep_failed(&self) -> bool {
+        self.keep_failed
+    }
+
+    // With hashing on, actual dependencies are checked against `state` by
+    // content instead of modification times.
+    pub fn set_hashing(&mut self, hashing: bool) {
+        self.hashing = hashing;
+    }
+
+    pub fn get_hashing(&self) -> bool {
+        self.hashing
+    }
+
+    // Actual targets are checked against the commands `state` says they were
+    // built with, and `state` is updated as targets are built.
+    pub fn set_state(&mut self, state: state::State) {
+        self.state = Some(Mutex::new(state));
+    }
+
+    pub fn take_state(&mut self) -> Option<state::State> {
+        self.state.take().map(|s| s.into_inner().unwrap())
+    }
 }
@@ -0,0 +1,196 @@
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::recipe::{Recipe, RunFunction, Thing};
+use crate::signals;
+use crate::Verbosity;
+
+// How often running commands are checked for having finished or for needing
+// to be interrupted.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Runs `cmd` with its output going to `log`, or to ours if there is none. The
+// command gets a process group of its own, to which SIGINT and SIGTERM caught
+// by us are forwarded. Outside of the foreground process group it would be
+// stopped when reading from the terminal, so it gets no input if ours is one.
+fn run_cmd(cmd: &str, log: Option<&fs::File>) -> io::Result<bool> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd).process_group(0);
+    if io::stdin().is_terminal() {
+        command.stdin(Stdio::null());
+    }
+    if let Some(mut log) = log {
+        writeln!(log, "$ {}", cmd)?;
+        command
+            .stdout(Stdio::from(log.try_clone()?))
+            .stderr(Stdio::from(log.try_clone()?));
+    }
+    let mut child = command.spawn()?;
+    let mut forwarded = false;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.code() == Some(0));
+        }
+        if let (Some(sig), false) = (signals::received(), forwarded) {
+            signals::forward(child.id(), sig);
+            forwarded = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Log files are named after their targets, with slashes escaped. Those of
+// pseudo targets start with "@", which is escaped in the names.
+fn log_path(dir: &Path, thing: &Thing) -> PathBuf {
+    let escape = |s: &str| {
+        s.replace('%', "%25")
+            .replace('/', "%2F")
+            .replace('@', "%40")
+    };
+    let name = match thing {
+        Thing::Actual(s) => escape(s),
+        Thing::Pseudo(s) => format!("@{}", escape(s)),
+    };
+    dir.join(format!("{}.log", name))
+}
+
+// Runs the commands of `thing` until one fails. With a log directory, their
+// output is captured in a log of the target, which is printed if the target
+// fails, and a status line is printed for the target.
+pub fn build(rec: &Recipe, thing: &Thing, cmds: &[String], log_dir: Option<&Path>) -> bool {
+    let log = log_dir.map(|dir| log_path(dir, thing));
+    let file = match &log {
+        None => None,
+        Some(path) => match fs::File::create(path) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("{}: unable to create log: {}", path.display(), e);
+                return false;
+            }
+        },
+    };
+    let mut ok = true;
+    for cmd in cmds.iter() {
+        if signals::received().is_some() {
+            return false;
+        }
+        condln!(rec.get_verbosity(), Verbosity::Verbose, "[cmd] {}", cmd);
+        match run_cmd(cmd, file.as_ref()) {
+            Ok(true) => (),
+            Ok(false) => ok = false,
+            Err(e) => {
+                eprintln!("Error when executing {}: {:?}", cmd, e);
+                ok = false;
+            }
+        }
+        if signals::received().is_some() {
+            eprintln!("Interrupted while building {}", thing);
+            return false;
+        }
+        if !ok {
+            break;
+        }
+    }
+    if let Some(path) = log {
+        if ok {
+            println!("[ok] {}", thing);
+        } else {
+            let output = fs::read_to_string(&path).unwrap_or_default();
+            eprint!("[failed] {}, log in {}:\n{}", thing, path.display(), output);
+        }
+    }
+    ok
+}
+
+// What is done with the commands of out-of-date targets.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    Build,
+    DryRun,
+    Question,
+}
+
+// Makes the function handling the commands of a target in `mode`. Questions
+// run nothing, and only set `stale` when there are commands that would run.
+pub fn runner(mode: Mode, log_dir: Option<&Path>, stale: Arc<AtomicBool>) -> RunFunction {
+    match mode {
+        Mode::Question => Box::new(move |rec: &Recipe, _: &Thing, cmds: &[String]| {
+            for cmd in cmds.iter() {
+                condln!(
+                    rec.get_verbosity(),
+                    Verbosity::Verbose,
+                    "[?] would run: {}",
+                    cmd
+                );
+            }
+            stale.store(true, Ordering::SeqCst);
+            true
+        }),
+        Mode::DryRun => Box::new(&|_: &Recipe, _: &Thing, cmds: &[String]| {
+            for cmd in cmds.iter() {
+                println!("{}", cmd);
+            }
+            true
+        }),
+        Mode::Build => {
+            let log_dir = log_dir.map(|d| d.to_path_buf());
+            Box::new(move |rec: &Recipe, thing: &Thing, cmds: &[String]| {
+                build(rec, thing, cmds, log_dir.as_deref())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn test_log_path() {
+        let dir = Path::new("logs");
+        let path = |thing: Thing| log_path(dir, &thing).display().to_string();
+        assert_eq!("logs/foo.o.log", path(Thing::Actual("foo.o".to_string())));
+        assert_eq!(
+            "logs/src%2Fa%25b%40c.log",
+            path(Thing::Actual("src/a%b@c".to_string()))
+        );
+        assert_eq!("logs/@all.log", path(Thing::Pseudo("all".to_string())));
+        assert_eq!("logs/%40all.log", path(Thing::Actual("@all".to_string())));
+    }
+
+    #[test]
+    fn test_build_log() {
+        let dir = TempDir::new("run");
+        let rec = Recipe::new();
+        let cmds = |cmds: &[&str]| cmds.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let log = |thing: &Thing| fs::read_to_string(log_path(&dir, thing)).unwrap();
+
+        // Both output streams are captured, after the command.
+        let thing = Thing::Actual("out".to_string());
+        assert!(build(
+            &rec,
+            &thing,
+            &cmds(&["echo one", "echo two >&2"]),
+            Some(&dir)
+        ));
+        assert_eq!("$ echo one\none\n$ echo two >&2\ntwo\n", log(&thing));
+
+        // Nothing runs after a failed command.
+        let thing = Thing::Pseudo("out".to_string());
+        assert!(!build(
+            &rec,
+            &thing,
+            &cmds(&["echo one; false", "echo two"]),
+            Some(&dir)
+        ));
+        assert_eq!("$ echo one; false\none\n", log(&thing));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::graph;
+use crate::graph::GraphIndex;
+
+// Book-keeping for handing out graph nodes in dependency order. A node becomes
+// ready once all of its dependencies have been reported done. Among the ready
+// nodes, the one appearing earliest in `order` is handed out first, which
+// makes a single-job build proceed in plain depth-first order.
+#[derive(Debug)]
+pub struct Schedule {
+    order: Vec<GraphIndex>,
+    rank: HashMap<GraphIndex, usize>,
+    pending: HashMap<GraphIndex, usize>,
+    parents: HashMap<GraphIndex, Vec<GraphIndex>>,
+    ready: BTreeSet<usize>,
+}
+
+impl Schedule {
+    // `order` must list every node reachable from the goal with dependencies
+    // before their dependents, like the post-order produced by
+    // `Recipe::toposort`.
+    pub fn new<T>(dag: &graph::DAG<T>, order: Vec<GraphIndex>) -> Schedule {
+        let rank: HashMap<GraphIndex, usize> =
+            order.iter().enumerate().map(|(r, &i)| (i, r)).collect();
+        let mut pending = HashMap::new();
+        let mut parents: HashMap<GraphIndex, Vec<GraphIndex>> = HashMap::new();
+        let mut ready = BTreeSet::new();
+        for (r, &i) in order.iter().enumerate() {
+            // Duplicate edges are counted once. Edges pointing forward in the
+            // order cannot be honored and are ignored.
+            let deps: HashSet<GraphIndex> = dag
+                .successors(i)
+                .unwrap()
+                .filter(|d| rank.get(d).is_some_and(|&dr| dr < r))
+                .collect();
+            if deps.is_empty() {
+                ready.insert(r);
+            }
+            pending.insert(i, deps.len());
+            for dep in deps {
+                parents.entry(dep).or_default().push(i);
+            }
+        }
+        Schedule {
+            order,
+            rank,
+            pending,
+            parents,
+            ready,
+        }
+    }
+
+    // Returns the next node whose dependencies are all done.
+    pub fn pop(&mut self) -> Option<GraphIndex> {
+        let r = *self.ready.iter().next()?;
+        self.ready.remove(&r);
+        Some(self.order[r])
+    }
+
+    // Returns the nodes still waiting for dependencies, that is, the ones that
+    // never became ready because something they depend on was not done.
+    pub fn blocked(&self) -> Vec<GraphIndex> {
+        self.order
+            .iter()
+            .filter(|i| *self.pending.get(i).unwrap() > 0)
+            .copied()
+            .collect()
+    }
+
+    // Marks `node` as done, possibly making its dependents ready.
+    pub fn done(&mut self, node: GraphIndex) {
+        if let Some(parents) = self.parents.get(&node) {
+            for parent in parents.iter() {
+                let n = self.pending.get_mut(parent).unwrap();
+                *n -= 1;
+                if *n == 0 {
+                    self.ready.insert(*self.rank.get(parent).unwrap());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sched_order() {
+        // 0 -> 1 -> 3
+        //   -> 2 -> 3
+        let mut dag: graph::DAG<u8> = graph::DAG::new();
+        let i0 = dag.node(0);
+        let i1 = dag.node(1);
+        let i2 = dag.node(2);
+        let i3 = dag.node(3);
+        dag.connect(i0, i1);
+        dag.connect(i0, i2);
+        dag.connect(i1, i3);
+        dag.connect(i2, i3);
+
+        let mut sched = Schedule::new(&dag, vec![i3, i2, i1, i0]);
+        assert_eq!(Some(i3), sched.pop());
+        assert_eq!(None, sched.pop());
+        sched.done(i3);
+        assert_eq!(Some(i2), sched.pop());
+        assert_eq!(Some(i1), sched.pop());
+        assert_eq!(None, sched.pop());
+        sched.done(i1);
+        assert_eq!(None, sched.pop());
+        sched.done(i2);
+        assert_eq!(Some(i0), sched.pop());
+        sched.done(i0);
+        assert_eq!(None, sched.pop());
+        assert!(sched.blocked().is_empty());
+    }
+
+    #[test]
+    fn test_sched_blocked() {
+        // 0 -> 1 -> 2
+        let mut dag: graph::DAG<u8> = graph::DAG::new();
+        let i0 = dag.node(0);
+        let i1 = dag.node(1);
+        let i2 = dag.node(2);
+        dag.connect(i0, i1);
+        dag.connect(i1, i2);
+
+        let mut sched = Schedule::new(&dag, vec![i2, i1, i0]);
+        assert_eq!(Some(i2), sched.pop());
+        // `i2` is never reported done.
+        assert_eq!(None, sched.pop());
+        assert_eq!(vec![i1, i0], sched.blocked());
+    }
+}
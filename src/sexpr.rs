@@ -1,7 +1,28 @@
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Pos {
+    pub lineno: u64,
+    pub col: u64,
+}
+
+impl Pos {
+    pub fn new(lineno: u64, col: u64) -> Pos {
+        Pos { lineno, col }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SExpr {
     Nil,
-    List(Vec<SExpr>),
-    Id(String),
-    Str(String),
+    List(Vec<SExpr>, Pos),
+    Id(String, Pos),
+    Str(String, Pos),
+}
+
+impl SExpr {
+    pub fn pos(&self) -> Pos {
+        match self {
+            SExpr::Nil => Pos::default(),
+            SExpr::List(_, pos) | SExpr::Id(_, pos) | SExpr::Str(_, pos) => *pos,
+        }
+    }
 }
@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+
+pub const SIGINT: i32 = 2;
+pub const SIGTERM: i32 = 15;
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+    fn kill(pid: i32, sig: i32) -> i32;
+}
+
+// The last signal caught, or zero.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(sig: i32) {
+    RECEIVED.store(sig, Ordering::SeqCst);
+}
+
+// Catches SIGINT and SIGTERM instead of dying, so that running commands can be
+// stopped in an orderly way. The signals are only noted here, and whoever
+// waits for commands has to check `received` and `forward` them.
+pub fn install() {
+    let handler = on_signal as extern "C" fn(i32) as usize;
+    unsafe {
+        signal(SIGINT, handler);
+        signal(SIGTERM, handler);
+    }
+}
+
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+// Sends `sig` to the process group led by `pid`.
+pub fn forward(pid: u32, sig: i32) -> bool {
+    unsafe { kill(-(pid as i32), sig) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    const CHILD: &str = "PMA_TEST_SIGNALS_CHILD";
+
+    // Installing the handlers would leave the whole test binary ignoring
+    // SIGINT and SIGTERM, so the test runs itself again in a child process.
+    #[test]
+    fn test_signals_received() {
+        if env::var_os(CHILD).is_none() {
+            let output = Command::new(env::current_exe().unwrap())
+                .args(["--exact", "signals::tests::test_signals_received"])
+                .env(CHILD, "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return;
+        }
+        install();
+        assert_eq!(None, received());
+        unsafe {
+            kill(std::process::id() as i32, SIGTERM);
+        }
+        // The signal may be handled by another thread, some time later.
+        for _ in 0..500 {
+            if received().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Some(SIGTERM), received());
+    }
+}
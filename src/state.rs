@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+// The first line of every state file.
+const HEADER: &str = "pma-state 1";
+
+// What was known about an actual target when it was last built.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Entry {
+    pub cmds: Vec<String>,
+    pub deps: Vec<(String, u64)>,
+    // Dependencies found in the depfile of the target.
+    pub extra: Vec<String>,
+}
+
+impl Entry {
+    pub fn dep_hash(&self, dep: &str) -> Option<u64> {
+        self.deps.iter().find(|(d, _)| d == dep).map(|(_, h)| *h)
+    }
+}
+
+// Build state kept between runs, keyed by target file name.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct State {
+    entries: HashMap<String, Entry>,
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            entries: HashMap::new(),
+        }
+    }
+
+    // Reads the state from `path`. A missing file gives an empty state.
+    pub fn load(path: &str) -> Result<State, String> {
+        match fs::read_to_string(path) {
+            Ok(s) => s.parse::<State>(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(State::new()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn get(&self, target: &str) -> Option<&Entry> {
+        self.entries.get(target)
+    }
+
+    pub fn record(&mut self, target: &str, entry: Entry) {
+        self.entries.insert(target.to_string(), entry);
+    }
+
+    pub fn forget(&mut self, target: &str) {
+        self.entries.remove(target);
+    }
+}
+
+// Fields are separated by tabs, so tabs, newlines and backslashes in names and
+// commands are escaped.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut ret = String::new();
+    let mut it = s.chars();
+    while let Some(c) = it.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match it.next() {
+            Some('\\') => ret.push('\\'),
+            Some('t') => ret.push('\t'),
+            Some('n') => ret.push('\n'),
+            _ => return Err(format!("invalid escape in {:?}", s)),
+        }
+    }
+    Ok(ret)
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        let mut targets: Vec<&String> = self.entries.keys().collect();
+        targets.sort();
+        for target in targets {
+            let entry = self.entries.get(target).unwrap();
+            writeln!(f, "target\t{}", escape(target))?;
+            for cmd in entry.cmds.iter() {
+                writeln!(f, "cmd\t{}", escape(cmd))?;
+            }
+            for (dep, hash) in entry.deps.iter() {
+                writeln!(f, "dep\t{}\t{:016x}", escape(dep), hash)?;
+            }
+            for extra in entry.extra.iter() {
+                writeln!(f, "extra\t{}", escape(extra))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for State {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<State, String> {
+        let mut lines = s.lines().enumerate();
+        match lines.next() {
+            Some((_, HEADER)) => (),
+            _ => return Err("not a state file".to_string()),
+        }
+        let mut state = State::new();
+        let mut cur: Option<(String, Entry)> = None;
+        for (n, line) in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let err = || format!("line {}: malformed entry", n + 1);
+            match (fields[0], &mut cur) {
+                ("target", _) if fields.len() == 2 => {
+                    if let Some((target, entry)) = cur.take() {
+                        state.record(&target, entry);
+                    }
+                    cur = Some((unescape(fields[1])?, Entry::default()));
+                }
+                ("cmd", Some((_, entry))) if fields.len() == 2 => {
+                    entry.cmds.push(unescape(fields[1])?);
+                }
+                ("extra", Some((_, entry))) if fields.len() == 2 => {
+                    entry.extra.push(unescape(fields[1])?);
+                }
+                ("dep", Some((_, entry))) if fields.len() == 3 => {
+                    let hash = u64::from_str_radix(fields[2], 16).map_err(|_| err())?;
+                    entry.deps.push((unescape(fields[1])?, hash));
+                }
+                _ => return Err(err()),
+            }
+        }
+        if let Some((target, entry)) = cur {
+            state.record(&target, entry);
+        }
+        Ok(state)
+    }
+}
+
+// 64-bit FNV-1a. Good enough for noticing changed files, and stable between
+// builds of pma unlike the standard library hasher.
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data.iter() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+pub fn hash_file(path: &str) -> io::Result<u64> {
+    Ok(hash_bytes(&fs::read(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let mut state = State::new();
+        state.record(
+            "foo.o",
+            Entry {
+                cmds: vec!["cc -c\t-o foo.o foo.c".to_string(), "echo \\n".to_string()],
+                deps: vec![("foo.c".to_string(), 0xdead_beef)],
+                extra: vec!["foo.h".to_string()],
+            },
+        );
+        state.record("empty", Entry::default());
+        let s = state.to_string();
+        assert_eq!(state, s.parse::<State>().unwrap());
+        assert_eq!(
+            Some(0xdead_beef),
+            state.get("foo.o").unwrap().dep_hash("foo.c")
+        );
+    }
+
+    #[test]
+    fn test_state_malformed() {
+        assert!("garbage".parse::<State>().is_err());
+        assert!("pma-state 1\ncmd\tls".parse::<State>().is_err());
+        assert!("pma-state 1\ntarget\tx\ndep\ty\tzz"
+            .parse::<State>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_state_hash() {
+        assert_eq!(0xcbf2_9ce4_8422_2325, hash_bytes(b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, hash_bytes(b"a"));
+    }
+}
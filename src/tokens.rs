@@ -56,6 +56,11 @@ impl Tokens {
         Some(self.toks[0].clone())
     }
 
+    // Looks `i` tokens ahead without popping anything.
+    pub fn get(&self, i: usize) -> Option<(&Token, u64, u64)> {
+        Some((self.toks.get(i)?, self.lineno[i], self.col[i]))
+    }
+
     pub fn lineno(&self) -> u64 {
         self.curlineno
     }
@@ -0,0 +1,65 @@
+use std::fs;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[path = "../src/tempdir.rs"]
+mod tempdir;
+use tempdir::TempDir;
+
+// Waits a while for `cond` to hold.
+fn wait_for(cond: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+// Whether process `pid` is still running, and not only waiting to be reaped.
+fn running(pid: &str) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => !stat.contains(") Z "),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn test_interrupt_stops_commands() {
+    let dir = TempDir::new("interrupt");
+
+    // The inner shell is a grandchild of pma, which only the signal sent to
+    // the whole process group of the command reaches.
+    fs::write(
+        dir.join("build.pma"),
+        r#"(target "out" () ("sh -c 'echo $$$$ > pid; exec sleep 30'; touch out"))"#,
+    )
+    .unwrap();
+    let pid = dir.join("pid");
+    for (sig, code) in [("TERM", 143), ("INT", 130)] {
+        let mut pma = Command::new(env!("CARGO_BIN_EXE_pma"))
+            .arg("out")
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        assert!(wait_for(
+            || fs::read_to_string(&pid).is_ok_and(|s| s.ends_with('\n'))
+        ));
+        let grandchild = fs::read_to_string(&pid).unwrap().trim().to_string();
+        let sent = Command::new("kill")
+            .arg(format!("-{}", sig))
+            .arg(pma.id().to_string())
+            .status()
+            .unwrap();
+        assert!(sent.success());
+        assert_eq!(Some(code), pma.wait().unwrap().code());
+        assert!(wait_for(|| !running(&grandchild)), "{} survived", sig);
+        assert!(!dir.join("out").exists());
+        fs::remove_file(&pid).unwrap();
+    }
+}
@@ -1,11 +1,21 @@
-use pma::eval::eval;
+use pma::eval::{eval, eval_file};
 use pma::lex::lex;
 use pma::parse::parse;
 use pma::recipe::Recipe;
+use pma::recipe::RegenFunction;
+use pma::recipe::SearchResult;
 use pma::recipe::Thing;
 use pma::recipe::Thing::{Actual, Pseudo};
+use pma::run::{runner, Mode};
+use pma::state::{Entry, State};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 
-static mut STATE: u8 = 0;
+#[path = "../src/tempdir.rs"]
+mod tempdir;
+use tempdir::TempDir;
+
+static STATE: AtomicU8 = AtomicU8::new(0);
 static TEST: &str = r###"# This is an example. The default target will be "all".
 
 (set "CC" "BUILD")
@@ -30,7 +40,7 @@ static TEST: &str = r###"# This is an example. The default target will be "all".
 
 #[test]
 fn test_whole() {
-    let toks = match lex(&TEST) {
+    let toks = match lex(TEST) {
         Err(e) => {
             panic!("{:?}", e);
         }
@@ -50,34 +60,33 @@ fn test_whole() {
     };
 
     rec.evaluate(
-        &Thing::Pseudo("all".to_string()),
-        Box::new(&|_: &Recipe, cmd: &String| {
+        &[Thing::Pseudo("all".to_string())],
+        Box::new(&|_: &Recipe, _: &Thing, cmds: &[String]| {
             fn advance() -> u8 {
-                unsafe {
-                    let ret = STATE;
-                    STATE += 1;
-                    println!("state={} -> {}", ret, STATE);
-                    return ret;
-                }
+                let ret = STATE.fetch_add(1, Ordering::SeqCst);
+                println!("state={} -> {}", ret, ret + 1);
+                ret
             }
-            println!("cmd: {:?}", cmd);
-            match cmd.as_str() {
-                "BUILD FOO_MAIN" => {
-                    if advance() != 0 {
-                        panic!("main rebuild not first");
+            for cmd in cmds.iter() {
+                println!("cmd: {:?}", cmd);
+                match cmd.as_str() {
+                    "BUILD FOO_MAIN" => {
+                        if advance() != 0 {
+                            panic!("main rebuild not first");
+                        }
                     }
-                }
-                "BUILD FOO" => {
-                    if advance() != 1 {
-                        panic!("foo rebuild not second");
+                    "BUILD FOO" => {
+                        if advance() != 1 {
+                            panic!("foo rebuild not second");
+                        }
                     }
-                }
-                "$NOTVAR" => {
-                    if advance() != 2 {
-                        panic!("all not third");
+                    "$NOTVAR" => {
+                        if advance() != 2 {
+                            panic!("all not third");
+                        }
                     }
+                    t => panic!("surprising cmd: {:?}", t),
                 }
-                t @ _ => panic!("surprising cmd: {:?}", t),
             }
             true
         }),
@@ -92,18 +101,453 @@ fn test_whole() {
             match (target, dep) {
                 (Actual(starget), Actual(sdep)) => match (starget.as_str(), sdep.as_str()) {
                     ("foo_main.o", "foo_main.c") => Ok(true),
-                    ("foo", "foo_main.o") => unsafe { Ok(STATE == 1) },
+                    ("foo", "foo_main.o") => Ok(STATE.load(Ordering::SeqCst) == 1),
                     (_, _) => Ok(false),
                 },
                 (Pseudo(starget), Actual(sdep)) => match (starget.as_str(), sdep.as_str()) {
-                    ("all", "foo") => unsafe { Ok(STATE == 2) },
+                    ("all", "foo") => Ok(STATE.load(Ordering::SeqCst) == 2),
                     (_, _) => Ok(false),
                 },
                 (_, _) => Ok(false),
             }
         }),
     );
-    unsafe {
-        assert_eq!(3, STATE);
+    assert_eq!(3, STATE.load(Ordering::SeqCst));
+}
+
+fn load(src: &str) -> Recipe {
+    let toks = lex(src).unwrap();
+    let sexprs = parse(toks).unwrap();
+    eval(sexprs.into_iter()).unwrap()
+}
+
+// Evaluates `targets` with the commands collected instead of run. Targets
+// fail when one of their commands is in `failing`.
+fn run_failing(
+    rec: &Recipe,
+    targets: &[Thing],
+    failing: &'static [&'static str],
+    regener: RegenFunction,
+) -> (SearchResult, Vec<String>) {
+    let run = Arc::new(Mutex::new(Vec::new()));
+    let collected = run.clone();
+    let res = rec.evaluate(
+        targets,
+        Box::new(move |_: &Recipe, _: &Thing, cmds: &[String]| {
+            collected.lock().unwrap().extend(cmds.iter().cloned());
+            cmds.iter().all(|cmd| !failing.contains(&cmd.as_str()))
+        }),
+        regener,
+    );
+    let run = run.lock().unwrap().clone();
+    (res, run)
+}
+
+fn run(rec: &Recipe, targets: &[Thing], regener: RegenFunction) -> Vec<String> {
+    run_failing(rec, targets, &[], regener).1
+}
+
+#[test]
+fn test_dry_run() {
+    let rec = load(TEST);
+
+    // Only "foo_main.o" looks out of date. Nothing is really run, so the
+    // targets depending on it must be regenerated because of the rebuild
+    // alone.
+    let printed = run(
+        &rec,
+        &[Pseudo("all".to_string())],
+        Box::new(&|target: &Thing, dep: &Thing| match (target, dep) {
+            (Actual(starget), Actual(sdep)) => Ok(starget == "foo_main.o" && sdep == "foo_main.c"),
+            (_, _) => Ok(false),
+        }),
+    );
+    assert_eq!(vec!["BUILD FOO_MAIN", "BUILD FOO", "$NOTVAR"], printed);
+}
+
+#[test]
+fn test_question() {
+    let rec = load(TEST);
+
+    // `pma -q` exits with 1 when something is out of date, and with 0 when
+    // everything is up to date.
+    let ask = |regen: bool| {
+        let stale = Arc::new(AtomicBool::new(false));
+        let res = rec.evaluate(
+            &[Pseudo("all".to_string())],
+            runner(Mode::Question, None, stale.clone()),
+            Box::new(move |_: &Thing, _: &Thing| Ok(regen)),
+        );
+        assert_eq!(SearchResult::Ok, res);
+        stale.load(Ordering::SeqCst)
+    };
+    assert!(ask(true));
+    assert!(!ask(false));
+}
+
+#[test]
+fn test_keep_going() {
+    let mut rec = load(TEST);
+    rec.set_keep_going(true);
+
+    // Everything is out of date and "foo_main.o" fails to build, which leaves
+    // out the targets depending on it but not "foo_util.o".
+    let (res, mut cmds) = run_failing(
+        &rec,
+        &[Pseudo("all".to_string())],
+        &["BUILD FOO_MAIN"],
+        Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+    );
+    assert_eq!(
+        SearchResult::Incomplete {
+            failed: vec![Actual("foo_main.o".to_string())],
+            skipped: vec![Actual("foo".to_string()), Pseudo("all".to_string())],
+        },
+        res
+    );
+    cmds.sort();
+    assert_eq!(vec!["BUILD FOO_MAIN", "BUILD UTIL"], cmds);
+}
+
+#[test]
+fn test_several_targets() {
+    let mut rec = load(TEST);
+    rec.set_jobs(4);
+
+    // Everything is out of date, yet "foo_util.o", which both targets need,
+    // is only built once.
+    let mut cmds = run(
+        &rec,
+        &[Actual("foo".to_string()), Actual("foo_util.o".to_string())],
+        Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+    );
+    cmds.sort();
+    assert_eq!(vec!["BUILD FOO", "BUILD FOO_MAIN", "BUILD UTIL"], cmds);
+}
+
+#[test]
+fn test_changed_commands() {
+    let mut rec = load(TEST);
+
+    // Everything is up to date by modification times, but "foo_util.o" was
+    // last built with other commands.
+    let mut state = State::new();
+    for (target, cmd) in [
+        ("foo", "BUILD FOO"),
+        ("foo_main.o", "BUILD FOO_MAIN"),
+        ("foo_util.o", "OLDBUILD UTIL"),
+    ] {
+        state.record(
+            target,
+            Entry {
+                cmds: vec![cmd.to_string()],
+                deps: vec![],
+                extra: vec![],
+            },
+        );
+    }
+    rec.set_state(state);
+    let changed = run(
+        &rec,
+        &[Actual("foo".to_string())],
+        Box::new(&|_: &Thing, _: &Thing| Ok(false)),
+    );
+    assert_eq!(vec!["BUILD UTIL", "BUILD FOO"], changed);
+    assert_eq!(
+        vec!["BUILD UTIL".to_string()],
+        rec.take_state().unwrap().get("foo_util.o").unwrap().cmds
+    );
+}
+
+#[test]
+fn test_hashing() {
+    let dir = TempDir::new("hashing");
+    let (src, out) = (dir.join("in.c"), dir.join("out.o"));
+    let (srcname, outname) = (src.display().to_string(), out.display().to_string());
+    std::fs::write(&src, "int x;").unwrap();
+    let recorded = pma::state::hash_file(&srcname).unwrap();
+
+    // Modification times always make the target look out of date, so only
+    // the contents recorded at the last build can tell otherwise.
+    let build = || {
+        let mut rec = load(&format!(r#"(target {:?} ({:?}) ("CC"))"#, out, src));
+        let mut state = State::new();
+        state.record(
+            &outname,
+            Entry {
+                cmds: vec!["CC".to_string()],
+                deps: vec![(srcname.clone(), recorded)],
+                extra: vec![],
+            },
+        );
+        rec.set_state(state);
+        rec.set_hashing(true);
+        run(
+            &rec,
+            &[Actual(outname.clone())],
+            Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+        )
+    };
+    std::fs::write(&out, "").unwrap();
+    assert!(build().is_empty());
+
+    // Changed contents rebuild the target, and so does a missing target.
+    std::fs::write(&src, "int y;").unwrap();
+    assert_eq!(vec!["CC"], build());
+    std::fs::write(&src, "int x;").unwrap();
+    assert!(build().is_empty());
+    std::fs::remove_file(&out).unwrap();
+    assert_eq!(vec!["CC"], build());
+}
+
+#[test]
+fn test_patterns() {
+    let mut rec = load(
+        r#"
+(target "prog" ("a.o" "b.o") ("LINK $TARGET"))
+(target "b.o" ("b.c") ("SPECIAL $TARGET"))
+(target "a.c" () ("GEN $TARGET"))
+(pattern "%.o" ("%.c") ("CC $TARGET $DEPS"))
+"#,
+    );
+
+    // There is nothing to make "x.o" from.
+    assert!(!rec.add_pattern_rules(&Actual("x.o".to_string())));
+    assert_eq!(Some(&Actual("prog".to_string())), rec.get_default());
+    let cmds = run(
+        &rec,
+        &[Actual("prog".to_string())],
+        Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+    );
+    assert_eq!(
+        vec!["SPECIAL b.o", "GEN a.c", "CC a.o a.c", "LINK prog"],
+        cmds
+    );
+}
+
+#[test]
+fn test_target_vars() {
+    let rec = load(
+        r#"
+(set "CFLAGS" "-O2")
+(set "MODE" "release")
+(target all (prog test) ())
+(target prog (a b) ("LD $CFLAGS"))
+(target test (a) ("TEST $CFLAGS $MODE") (set "CFLAGS" "-g") (set "MODE" "debug"))
+(target a () ("CC $CFLAGS $MODE $TARGET"))
+(target b () ("CC $CFLAGS $MODE $TARGET") (set "CFLAGS" "$CFLAGS -Wall") (set "TARGET" "x"))
+"#,
+    );
+    let build = |target: &str| {
+        let mut cmds = run(
+            &rec,
+            &[Pseudo(target.to_string())],
+            Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+        );
+        cmds.sort();
+        cmds
+    };
+
+    // "a" is pulled in by "prog" first, and $TARGET cannot be overridden.
+    assert_eq!(
+        vec![
+            "CC -O2 -Wall release b",
+            "CC -O2 release a",
+            "LD -O2",
+            "TEST -g debug"
+        ],
+        build("all")
+    );
+    assert_eq!(vec!["CC -g debug a", "TEST -g debug"], build("test"));
+}
+
+#[test]
+fn test_depfile_extra() {
+    let mut rec = load(TEST);
+
+    // Everything is up to date by modification times, but a header listed in
+    // the depfile of "foo_util.o" at the last build has disappeared since.
+    let mut state = State::new();
+    for (target, cmd, extra) in [
+        ("foo", "BUILD FOO", vec![]),
+        ("foo_main.o", "BUILD FOO_MAIN", vec![]),
+        (
+            "foo_util.o",
+            "BUILD UTIL",
+            vec!["no/such/header.h".to_string()],
+        ),
+    ] {
+        state.record(
+            target,
+            Entry {
+                cmds: vec![cmd.to_string()],
+                deps: vec![],
+                extra,
+            },
+        );
+    }
+    rec.set_state(state);
+    let cmds = run(
+        &rec,
+        &[Actual("foo".to_string())],
+        Box::new(&|_: &Thing, _: &Thing| Ok(false)),
+    );
+    assert_eq!(vec!["BUILD UTIL", "BUILD FOO"], cmds);
+}
+
+#[test]
+fn test_outside_values() {
+    // Values from the command line or from the environment are never expanded,
+    // wherever they end up.
+    std::env::set_var("PMA_TEST_DOLLAR", "a$b");
+    let src = r#"
+(set "BUILD" "$CC -c")
+(set "FLAGS" (env "PMA_TEST_DOLLAR"))
+(set "COPY" (var "FLAGS"))
+(target t ((var "X") (map "%" "%.o" (var "X")))
+    ("$BUILD $FLAGS $COPY $OPT $X")
+    (set "OPT" (env "PMA_TEST_DOLLAR")))
+"#;
+    let overrides = vec![
+        ("CC".to_string(), "$HOME/cc".to_string()),
+        ("X".to_string(), "$y".to_string()),
+    ];
+    let sexprs = parse(lex(src).unwrap()).unwrap();
+    let rec = eval_file(sexprs.into_iter(), "x.pma", None, &overrides).unwrap();
+    let missing: Vec<String> = rec
+        .check_missing(&[Pseudo("t".to_string())])
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+    assert_eq!(
+        vec![
+            r#"x.pma:5: build error: no rule to make "$y", needed by t"#,
+            r#"x.pma:5: build error: no rule to make "$y.o", needed by t"#,
+        ],
+        missing
+    );
+    let cmds = run(
+        &rec,
+        &[Pseudo("t".to_string())],
+        Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+    );
+    assert_eq!(vec!["$HOME/cc -c a$b a$b a$b $y"], cmds);
+}
+
+#[test]
+fn test_graph() {
+    let rec = load(TEST);
+
+    // Only "foo_main.o" looks out of date, which makes the things depending on
+    // it stale as well.
+    let graph = rec
+        .graph(
+            &[Pseudo("all".to_string())],
+            Box::new(&|target: &Thing, dep: &Thing| match (target, dep) {
+                (Actual(starget), Actual(sdep)) => {
+                    Ok(starget == "foo_main.o" && sdep == "foo_main.c")
+                }
+                (_, _) => Ok(false),
+            }),
+        )
+        .unwrap();
+    let stale: Vec<String> = graph
+        .nodes
+        .iter()
+        .filter(|n| n.stale)
+        .map(|n| n.thing.to_string())
+        .collect();
+    assert_eq!(vec![r#""foo_main.o""#, r#""foo""#, "all"], stale);
+    let leaves = graph.nodes.iter().filter(|n| !n.rule).count();
+    assert_eq!(2, leaves);
+    assert_eq!(5, graph.edges.len());
+}
+
+#[test]
+fn test_failed_targets() {
+    let dir = TempDir::new("failed");
+    let (partial, precious) = (dir.join("partial.o"), dir.join("precious.o"));
+    let mut rec = load(&format!(
+        r#"
+(target all ({:?} {:?}) ())
+(target {:?} () ("WRITE"))
+(target {:?} () ("WRITE") (precious))
+"#,
+        partial, precious, partial, precious
+    ));
+
+    // Both targets are half written before their commands fail, but only the
+    // precious one is left behind.
+    rec.set_keep_going(true);
+    rec.evaluate(
+        &[Pseudo("all".to_string())],
+        Box::new(&|_: &Recipe, thing: &Thing, _: &[String]| {
+            if let Actual(name) = thing {
+                std::fs::write(name, "half").unwrap();
+            }
+            false
+        }),
+        Box::new(&|_: &Thing, _: &Thing| Ok(true)),
+    );
+    assert!(!partial.exists());
+    assert!(precious.exists());
+}
+
+#[test]
+fn test_missing_sources() {
+    let rec = load(TEST);
+    let missing: Vec<String> = rec
+        .check_missing(&[Pseudo("all".to_string())])
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+    assert_eq!(
+        vec![
+            r#"12: build error: no rule to make "foo_main.c", needed by "foo_main.o""#,
+            r#"17: build error: no rule to make "foo_util.c", needed by "foo_util.o""#,
+        ],
+        missing
+    );
+
+    // Tests run in the package directory.
+    let rec = load(r#"(target "x" ("Cargo.toml") ("CAT"))"#);
+    assert!(rec.check_missing(&[Actual("x".to_string())]).is_ok());
+}
+
+#[test]
+fn test_automatic_vars() {
+    let dir = TempDir::new("automatic");
+    let d = dir.display();
+    let now = std::time::SystemTime::now();
+    for (name, age) in [("old.h", 20), ("main.o", 10), ("main.c", 0)] {
+        let f = std::fs::File::create(dir.join(name)).unwrap();
+        f.set_modified(now - std::time::Duration::from_secs(age))
+            .unwrap();
     }
+    let mut rec = load(&format!(
+        r#"
+(pattern "{d}/%.o" ("{d}/%.c" "{d}/old.h" gen) ("$STEM|$FIRST_DEP|$NEWER_DEPS|$FILE_DEPS|$TARGET_DIR|$TARGET_BASE"))
+(target gen () ())
+"#
+    ));
+    let target = Actual(format!("{d}/main.o"));
+    assert!(rec.add_pattern_rules(&target));
+    rec.set_state(State::new());
+    let cmds = run(&rec, &[target], Box::new(&|_: &Thing, _: &Thing| Ok(true)));
+    let deps = format!("{d}/main.c {d}/old.h");
+    assert_eq!(
+        vec![format!("main|{d}/main.c|{d}/main.c gen|{deps}|{d}|main.o")],
+        cmds
+    );
+    // The recorded commands do not change with what is newer.
+    assert_eq!(
+        vec![format!("main|{d}/main.c|{deps} gen|{deps}|{d}|main.o")],
+        rec.take_state()
+            .unwrap()
+            .get(&format!("{d}/main.o"))
+            .unwrap()
+            .cmds
+    );
 }